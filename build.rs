@@ -13,7 +13,7 @@ fn main() {
             status
                 .success()
                 .then_some(())
-                .ok_or(io::Error::new(io::ErrorKind::Other, "yarn failed"))
+                .ok_or(io::Error::other("yarn failed"))
         })
         .expect("failed to build frontend");
 }
@@ -0,0 +1,207 @@
+//! Runtime moderation API
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use poem::http::StatusCode;
+use poem::web::{Data, Json, Path};
+use poem::{delete, get, handler, post, put, Endpoint, EndpointExt, IntoResponse, Response, Route};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use ulid::Ulid;
+
+use crate::alias::Aliases;
+use crate::middleware::{GroupFilter, Moderation, MuteEntry, Outlet};
+use crate::review::ReviewQueue;
+
+#[derive(Serialize, Debug)]
+struct Settings {
+    blacklist: Vec<String>,
+    dedup_window: i32,
+    mutes: Vec<MuteEntry>,
+    review: Vec<SmolStr>,
+    /// Packets dropped by the group allow/deny list
+    filtered: u64,
+    aliases: HashMap<SmolStr, Vec<SmolStr>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PatternRequest {
+    pattern: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct DedupRequest {
+    window: i32,
+}
+
+#[derive(Deserialize, Debug)]
+struct ReviewRequest {
+    group: SmolStr,
+}
+
+pub fn route(
+    moderation: Arc<Moderation>,
+    review: Arc<ReviewQueue>,
+    outlet: Arc<Outlet>,
+    filter: Arc<GroupFilter>,
+    aliases: Arc<Aliases>,
+) -> impl Endpoint {
+    Route::new()
+        .at("/", get(settings))
+        .at("/blacklist", post(add_pattern).delete(remove_pattern))
+        .at("/blacklist/reload", post(reload_blacklist))
+        .at("/aliases/reload", post(reload_aliases))
+        .at("/dedup", put(set_dedup))
+        .at("/mutes", post(mute).delete(unmute))
+        .at("/review", post(enable_review).delete(disable_review))
+        .at("/danmaku/:id", delete(delete_danmaku))
+        .data(moderation)
+        .data(review)
+        .data(outlet)
+        .data(filter)
+        .data(aliases)
+}
+
+#[handler]
+fn settings(
+    Data(moderation): Data<&Arc<Moderation>>,
+    Data(review): Data<&Arc<ReviewQueue>>,
+    Data(filter): Data<&Arc<GroupFilter>>,
+    Data(aliases): Data<&Arc<Aliases>>,
+) -> Json<Settings> {
+    Json(Settings {
+        blacklist: moderation.blacklist.patterns(),
+        dedup_window: moderation.dedup_window(),
+        mutes: moderation.mutes.entries(),
+        review: review.groups(),
+        filtered: filter.dropped(),
+        aliases: aliases.aliases(),
+    })
+}
+
+#[handler]
+#[tracing::instrument(skip(moderation))]
+fn add_pattern(
+    Json(req): Json<PatternRequest>,
+    Data(moderation): Data<&Arc<Moderation>>,
+) -> Response {
+    match moderation.blacklist.add(&req.pattern) {
+        Ok(()) => {
+            tracing::info!("added blacklist pattern: {}", req.pattern);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip(moderation))]
+fn remove_pattern(
+    Json(req): Json<PatternRequest>,
+    Data(moderation): Data<&Arc<Moderation>>,
+) -> Response {
+    match moderation.blacklist.remove(&req.pattern) {
+        Ok(()) => {
+            tracing::info!("removed blacklist pattern: {}", req.pattern);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    }
+}
+
+/// Also served at `/blacklist/reload` on the private root, where it was first exposed
+#[handler]
+#[tracing::instrument(skip_all)]
+pub fn reload_blacklist(Data(moderation): Data<&Arc<Moderation>>) -> Response {
+    match moderation.blacklist.reload() {
+        Ok(len) => (StatusCode::OK, format!("loaded {} patterns", len)).into_response(),
+        Err(e) => {
+            tracing::error!("failed to reload blacklist, keeping previous one: {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip_all)]
+fn reload_aliases(Data(aliases): Data<&Arc<Aliases>>) -> Response {
+    match aliases.reload() {
+        Ok(len) => (StatusCode::OK, format!("loaded {} aliases", len)).into_response(),
+        Err(e) => {
+            tracing::error!("failed to reload aliases, keeping previous ones: {}", e);
+            (StatusCode::BAD_REQUEST, e.to_string()).into_response()
+        }
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip(moderation))]
+fn set_dedup(
+    Json(req): Json<DedupRequest>,
+    Data(moderation): Data<&Arc<Moderation>>,
+) -> StatusCode {
+    moderation.set_dedup_window(req.window);
+    tracing::info!("dedup window set to {}", req.window);
+    StatusCode::NO_CONTENT
+}
+
+#[handler]
+#[tracing::instrument(skip(moderation))]
+fn mute(Json(entry): Json<MuteEntry>, Data(moderation): Data<&Arc<Moderation>>) -> Response {
+    match moderation.mutes.mute(entry) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::error!("failed to save mute list: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip(moderation))]
+fn unmute(Json(entry): Json<MuteEntry>, Data(moderation): Data<&Arc<Moderation>>) -> Response {
+    match moderation.mutes.unmute(&entry) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => {
+            tracing::error!("failed to save mute list: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response()
+        }
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip(review))]
+fn enable_review(
+    Json(req): Json<ReviewRequest>,
+    Data(review): Data<&Arc<ReviewQueue>>,
+) -> StatusCode {
+    tracing::info!("review enabled for {}", req.group);
+    review.enable(req.group);
+    StatusCode::NO_CONTENT
+}
+
+#[handler]
+#[tracing::instrument(skip(review))]
+fn disable_review(
+    Json(req): Json<ReviewRequest>,
+    Data(review): Data<&Arc<ReviewQueue>>,
+) -> StatusCode {
+    tracing::info!("review disabled for {}", req.group);
+    review.disable(&req.group);
+    StatusCode::NO_CONTENT
+}
+
+#[handler]
+#[tracing::instrument(skip(review, outlet))]
+fn delete_danmaku(
+    Path(id): Path<Ulid>,
+    Data(review): Data<&Arc<ReviewQueue>>,
+    Data(outlet): Data<&Arc<Outlet>>,
+) -> StatusCode {
+    if review.withdraw(|packet| packet.id == Some(id)) || outlet.delete(id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
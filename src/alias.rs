@@ -0,0 +1,125 @@
+//! Public aliases of upstream groups
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use eyre::Result;
+use smol_str::SmolStr;
+
+use crate::config::{watch_file, Config};
+
+/// Mapping from public alias to upstream groups, reloadable at runtime
+pub struct Aliases {
+    path: Option<PathBuf>,
+    aliases: RwLock<HashMap<SmolStr, Vec<SmolStr>>>,
+}
+
+impl Aliases {
+    pub fn from_config(config: &Config) -> Self {
+        let aliases = Self {
+            path: config.aliases_path.clone(),
+            aliases: RwLock::new(HashMap::new()),
+        };
+        if let Err(e) = aliases.reload() {
+            tracing::error!("failed to load aliases: {}", e);
+        }
+        aliases
+    }
+
+    /// Reload aliases from disk, keeping the previous ones on error
+    pub fn reload(&self) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let aliases: HashMap<SmolStr, Vec<SmolStr>> =
+            serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let len = aliases.len();
+        *self.aliases.write().unwrap() = aliases;
+        tracing::info!("loaded {} aliases from {}", len, path.display());
+        Ok(len)
+    }
+
+    /// Poll the aliases file and reload it on change
+    pub async fn watch(self: Arc<Self>) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        watch_file(path, || {
+            if let Err(e) = self.reload() {
+                tracing::error!("failed to reload aliases, keeping previous ones: {}", e);
+            }
+        })
+        .await
+    }
+
+    pub fn aliases(&self) -> HashMap<SmolStr, Vec<SmolStr>> {
+        self.aliases.read().unwrap().clone()
+    }
+
+    /// Resolve a public id to upstream groups, ids without alias are groups themselves
+    pub fn resolve(&self, id: &SmolStr) -> Vec<SmolStr> {
+        self.aliases
+            .read()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| vec![id.clone()])
+    }
+
+    /// The first of the requested ids that covers an upstream group
+    pub fn origin(&self, ids: &[SmolStr], group: &SmolStr) -> Option<SmolStr> {
+        let aliases = self.aliases.read().unwrap();
+        ids.iter()
+            .find(|&id| match aliases.get(id) {
+                Some(groups) => groups.contains(group),
+                None => id == group,
+            })
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(aliases: &[(&str, &[&str])]) -> HashMap<SmolStr, Vec<SmolStr>> {
+        aliases
+            .iter()
+            .map(|&(alias, groups)| (alias.into(), groups.iter().map(|&g| g.into()).collect()))
+            .collect()
+    }
+
+    fn origin(aliases: &Aliases, ids: &[&str], group: &str) -> Option<SmolStr> {
+        let ids = ids.iter().map(|&id| id.into()).collect::<Vec<_>>();
+        aliases.origin(&ids, &group.into())
+    }
+
+    #[test]
+    fn origin_is_the_requested_id() {
+        let aliases = Aliases {
+            path: None,
+            aliases: RwLock::new(table(&[("stage", &["1", "2"]), ("left", &["1"])])),
+        };
+        assert_eq!(origin(&aliases, &["stage"], "1").unwrap(), "stage");
+        assert_eq!(origin(&aliases, &["stage"], "2").unwrap(), "stage");
+        assert_eq!(origin(&aliases, &["stage"], "3"), None);
+        assert_eq!(origin(&aliases, &["stage", "3"], "3").unwrap(), "3");
+        assert_eq!(origin(&aliases, &["left", "stage"], "1").unwrap(), "left");
+        assert_eq!(origin(&aliases, &["left", "stage"], "2").unwrap(), "stage");
+        // group numbers requested directly are not hidden behind aliases
+        assert_eq!(origin(&aliases, &["1"], "1").unwrap(), "1");
+    }
+
+    #[test]
+    fn origin_follows_alias_changes() {
+        let aliases = Aliases {
+            path: None,
+            aliases: RwLock::new(table(&[("stage", &["1"])])),
+        };
+        assert_eq!(origin(&aliases, &["stage"], "2"), None);
+
+        *aliases.aliases.write().unwrap() = table(&[("stage", &["1", "2"])]);
+        assert_eq!(origin(&aliases, &["stage"], "2").unwrap(), "stage");
+    }
+}
@@ -0,0 +1,310 @@
+//! Persistent danmaku archive
+
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+
+use eyre::Result;
+use poem::http::StatusCode;
+use poem::web::{Data, Path, Query};
+use poem::{handler, IntoResponse, Response};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::config::Config;
+use crate::danmaku::{unix_millis, DanmakuPacket, Upstream};
+
+/// Interval between two retention purges
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Archived danmaku row
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Record {
+    /// Receive time in milliseconds since unix epoch
+    pub timestamp: i64,
+    pub group: SmolStr,
+    pub text: String,
+    pub color: Option<String>,
+    pub size: Option<f64>,
+    pub sender: Option<String>,
+    pub source: Upstream,
+}
+
+impl Record {
+    fn new(packet: &DanmakuPacket) -> Self {
+        Self {
+            timestamp: packet
+                .received_at
+                .unwrap_or_else(|| unix_millis(SystemTime::now())),
+            group: packet.group.clone(),
+            text: packet.danmaku.text.to_string(),
+            color: packet.danmaku.color.as_deref().map(Into::into),
+            size: packet.danmaku.size,
+            sender: packet.danmaku.sender.as_deref().map(Into::into),
+            source: packet.source,
+        }
+    }
+}
+
+/// SQLite backed danmaku archive
+///
+/// Writes go through a dedicated thread so that the middleware task never blocks on disk.
+pub struct Archive {
+    path: PathBuf,
+    writer: mpsc::Sender<Record>,
+}
+
+impl Archive {
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        let Some(path) = config.archive_path.clone() else {
+            return Ok(None);
+        };
+        let retention = (config.archive_retention > 0)
+            .then(|| Duration::from_secs(config.archive_retention as u64 * 86400));
+        Self::open(path, retention).map(Some)
+    }
+
+    fn open(path: PathBuf, retention: Option<Duration>) -> Result<Self> {
+        let conn = Connection::open(&path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            CREATE TABLE IF NOT EXISTS danmaku (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                timestamp INTEGER NOT NULL,
+                grp TEXT NOT NULL,
+                text TEXT NOT NULL,
+                color TEXT,
+                size REAL,
+                sender TEXT,
+                source TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS danmaku_grp_timestamp ON danmaku (grp, timestamp);",
+        )?;
+        tracing::info!("archiving danmaku to {}", path.display());
+
+        let (writer, receiver) = mpsc::channel();
+        std::thread::spawn(move || run_writer(conn, receiver, retention));
+
+        Ok(Self { path, writer })
+    }
+
+    /// Archive a danmaku that has passed the middleware chain
+    pub fn push(&self, packet: &DanmakuPacket) {
+        if self.writer.send(Record::new(packet)).is_err() {
+            tracing::error!("archive writer is gone, dropping {}", packet.danmaku);
+        }
+    }
+
+    /// Load the archive of a group within a time range (in milliseconds since unix epoch)
+    pub fn export(&self, group: &str, from: i64, to: i64) -> Result<Vec<Record>> {
+        let conn = Connection::open(&self.path)?;
+        let mut stmt = conn.prepare(
+            "SELECT timestamp, grp, text, color, size, sender, source FROM danmaku
+            WHERE grp = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY timestamp, id",
+        )?;
+        let records = stmt
+            .query_map(params![group, from, to], |row| {
+                let source: String = row.get(6)?;
+                Ok(Record {
+                    timestamp: row.get(0)?,
+                    group: row.get::<_, String>(1)?.into(),
+                    text: row.get(2)?,
+                    color: row.get(3)?,
+                    size: row.get(4)?,
+                    sender: row.get(5)?,
+                    source: source.parse().unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(records)
+    }
+}
+
+fn run_writer(conn: Connection, receiver: mpsc::Receiver<Record>, retention: Option<Duration>) {
+    let mut last_purge = None;
+    loop {
+        if let Some(retention) = retention {
+            if last_purge
+                .is_none_or(|time: SystemTime| time.elapsed().unwrap_or_default() >= PURGE_INTERVAL)
+            {
+                let now = SystemTime::now();
+                let cutoff = unix_millis(now - retention);
+                match conn.execute("DELETE FROM danmaku WHERE timestamp < ?1", [cutoff]) {
+                    Ok(n) => tracing::debug!("purged {} archived danmaku", n),
+                    Err(e) => tracing::error!("failed to purge archive: {}", e),
+                }
+                last_purge = Some(now);
+            }
+        }
+
+        let record = match receiver.recv_timeout(PURGE_INTERVAL) {
+            Ok(record) => record,
+            Err(mpsc::RecvTimeoutError::Timeout) => continue,
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        };
+        if let Err(e) = conn.execute(
+            "INSERT INTO danmaku (timestamp, grp, text, color, size, sender, source)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                record.timestamp,
+                record.group.as_str(),
+                record.text,
+                record.color,
+                record.size,
+                record.sender,
+                record.source.as_str(),
+            ],
+        ) {
+            tracing::error!("failed to archive danmaku: {}", e);
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Jsonl,
+    Csv,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportQuery {
+    /// Range start in seconds since unix epoch, inclusive
+    from: Option<i64>,
+    /// Range end in seconds since unix epoch, exclusive
+    to: Option<i64>,
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[handler]
+#[tracing::instrument(skip(archive))]
+pub async fn export(
+    Path(group): Path<SmolStr>,
+    Query(query): Query<ExportQuery>,
+    Data(archive): Data<&std::sync::Arc<Archive>>,
+) -> Response {
+    let archive = archive.clone();
+    let from = query.from.map_or(i64::MIN, |t| t.saturating_mul(1000));
+    let to = query.to.map_or(i64::MAX, |t| t.saturating_mul(1000));
+    let format = query.format;
+
+    let result = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let records = archive.export(&group, from, to)?;
+        let mut body = vec![];
+        match format {
+            ExportFormat::Jsonl => {
+                for record in records {
+                    serde_json::to_writer(&mut body, &record)?;
+                    body.push(b'\n');
+                }
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(&mut body);
+                for record in records {
+                    writer.serialize(record)?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(body)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(body)) => {
+            let content_type = match format {
+                ExportFormat::Jsonl => "application/x-ndjson",
+                ExportFormat::Csv => "text/csv; charset=utf-8",
+            };
+            body.with_content_type(content_type).into_response()
+        }
+        Ok(Err(e)) => {
+            tracing::error!("failed to export archive: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(e) => {
+            tracing::error!("export task failed: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use poem::test::TestClient;
+    use poem::{get, EndpointExt, Route};
+    use ulid::Ulid;
+
+    use super::*;
+
+    fn packet(group: &str, received_at: i64, text: &str, source: Upstream) -> DanmakuPacket {
+        let mut packet: DanmakuPacket = serde_json::from_value(serde_json::json!({
+            "group": group,
+            "danmaku": { "text": text, "color": "red", "size": 30.0, "sender": "alice" },
+        }))
+        .unwrap();
+        packet.received_at = Some(received_at);
+        packet.source = source;
+        packet
+    }
+
+    #[tokio::test]
+    async fn exports_archived_danmaku() {
+        let path = std::env::temp_dir().join(format!("danmaku-archive-{}.db", Ulid::new()));
+        let archive = Arc::new(Archive::open(path.clone(), None).unwrap());
+        archive.push(&packet("1", 999, "early", Upstream::Raw));
+        archive.push(&packet("1", 1000, "first", Upstream::OneBot));
+        archive.push(&packet("2", 1500, "other", Upstream::Raw));
+        archive.push(&packet("1", 1999, "last", Upstream::Webhook));
+        archive.push(&packet("1", 2000, "late", Upstream::Raw));
+
+        // writes go through the writer thread
+        let mut records = vec![];
+        for _ in 0..100 {
+            records = archive.export("1", i64::MIN, i64::MAX).unwrap();
+            if records.len() == 4 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let sources = records.iter().map(|r| r.source).collect::<Vec<_>>();
+        assert_eq!(
+            sources,
+            [
+                Upstream::Raw,
+                Upstream::OneBot,
+                Upstream::Webhook,
+                Upstream::Raw
+            ]
+        );
+
+        // `from` and `to` are in seconds, `from` inclusive and `to` exclusive
+        let client = TestClient::new(Route::new().at("/archive/:id", get(export.data(archive))));
+        let response = client
+            .get("/archive/1")
+            .query("from", &1)
+            .query("to", &2)
+            .query("format", &"csv")
+            .send()
+            .await;
+        response.assert_status_is_ok();
+        response
+            .assert_text(
+                "timestamp,group,text,color,size,sender,source\n\
+                1000,1,first,red,30.0,alice,one_bot\n\
+                1999,1,last,red,30.0,alice,webhook\n",
+            )
+            .await;
+
+        for suffix in ["", "-wal", "-shm"] {
+            let mut file = path.clone().into_os_string();
+            file.push(suffix);
+            std::fs::remove_file(file).ok();
+        }
+    }
+}
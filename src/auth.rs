@@ -0,0 +1,163 @@
+//! Access tokens for danmaku endpoints
+
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use eyre::Result;
+use poem::http::{header, HeaderMap};
+use serde::Deserialize;
+use smol_str::SmolStr;
+
+use crate::config::Config;
+
+/// Token passed in the query string
+#[derive(Deserialize, Debug, Default)]
+pub struct TokenQuery {
+    pub token: Option<String>,
+}
+
+/// Tokens file content
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+struct TokensFile {
+    /// Read tokens of each group
+    read: HashMap<SmolStr, HashSet<String>>,
+    /// Write tokens for upstreams
+    write: HashSet<String>,
+}
+
+/// Access tokens, endpoints without configured tokens are open
+#[derive(Debug, Default)]
+pub struct Tokens {
+    read_all: Option<String>,
+    read: HashMap<SmolStr, HashSet<String>>,
+    write: HashSet<String>,
+}
+
+impl Tokens {
+    pub fn from_config(config: &Config) -> Result<Self> {
+        let file = match &config.tokens_path {
+            Some(path) => Self::load(path)?,
+            None => TokensFile::default(),
+        };
+        let mut write = file.write;
+        write.extend(config.write_token.clone());
+        Ok(Self {
+            read_all: config.read_token.clone(),
+            read: file.read,
+            write,
+        })
+    }
+
+    fn load(path: &PathBuf) -> Result<TokensFile> {
+        let file = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        tracing::info!("loaded tokens from {}", path.display());
+        Ok(file)
+    }
+
+    /// Check the token of a client reading a group
+    pub fn check_read(&self, group: &str, token: Option<&str>) -> bool {
+        let tokens = self.read.get(group);
+        if self.read_all.is_none() && tokens.is_none() {
+            return true;
+        }
+        let Some(token) = token else {
+            return false;
+        };
+        self.read_all.as_deref() == Some(token) || tokens.is_some_and(|t| t.contains(token))
+    }
+
+    /// Check the token of an upstream writing danmaku
+    pub fn check_write(&self, token: Option<&str>) -> bool {
+        self.write.is_empty() || token.is_some_and(|token| self.write.contains(token))
+    }
+}
+
+/// Extract the token from the `Authorization: Bearer` header
+pub fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(read_all: Option<&str>, read: &[(&str, &str)], write: &[&str]) -> Tokens {
+        let mut groups = HashMap::<SmolStr, HashSet<String>>::new();
+        for &(group, token) in read {
+            groups
+                .entry(group.into())
+                .or_default()
+                .insert(token.to_string());
+        }
+        Tokens {
+            read_all: read_all.map(String::from),
+            read: groups,
+            write: write.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn read_is_open_without_tokens() {
+        let tokens = tokens(None, &[], &[]);
+        assert!(tokens.check_read("1", None));
+        assert!(tokens.check_read("1", Some("any")));
+    }
+
+    #[test]
+    fn read_all_applies_to_every_group() {
+        let tokens = tokens(Some("all"), &[], &[]);
+        assert!(tokens.check_read("1", Some("all")));
+        assert!(tokens.check_read("2", Some("all")));
+        assert!(!tokens.check_read("1", None));
+        assert!(!tokens.check_read("1", Some("wrong")));
+    }
+
+    #[test]
+    fn group_tokens_only_apply_to_their_group() {
+        let tokens = tokens(None, &[("1", "one")], &[]);
+        assert!(tokens.check_read("1", Some("one")));
+        assert!(!tokens.check_read("1", None));
+        assert!(!tokens.check_read("1", Some("wrong")));
+        // groups without tokens stay open
+        assert!(tokens.check_read("2", None));
+    }
+
+    #[test]
+    fn read_all_and_group_tokens_combine() {
+        let tokens = tokens(Some("all"), &[("1", "one")], &[]);
+        assert!(tokens.check_read("1", Some("all")));
+        assert!(tokens.check_read("1", Some("one")));
+        assert!(!tokens.check_read("2", Some("one")));
+        assert!(tokens.check_read("2", Some("all")));
+        assert!(!tokens.check_read("2", None));
+    }
+
+    #[test]
+    fn write_is_open_only_without_tokens() {
+        let open = tokens(None, &[], &[]);
+        assert!(open.check_write(None));
+        assert!(open.check_write(Some("any")));
+
+        let tokens = tokens(None, &[], &["a", "b"]);
+        assert!(tokens.check_write(Some("a")));
+        assert!(tokens.check_write(Some("b")));
+        assert!(!tokens.check_write(None));
+        assert!(!tokens.check_write(Some("c")));
+    }
+
+    #[test]
+    fn extracts_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer abc ".parse().unwrap());
+        assert_eq!(bearer(&headers), Some("abc"));
+    }
+}
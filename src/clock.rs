@@ -0,0 +1,47 @@
+//! Time source, mockable in tests
+
+use std::time::Instant;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use mock::MockClock;
+
+#[cfg(test)]
+mod mock {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    /// Clock that only moves when told to
+    #[derive(Clone)]
+    pub struct MockClock(Arc<Mutex<Instant>>);
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        pub fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+}
@@ -1,8 +1,12 @@
 use std::net::IpAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 
 use envconfig::Envconfig;
 
+use crate::middleware::OverflowPolicy;
+
 #[derive(Envconfig, Debug)]
 pub struct Config {
     /// Bind address
@@ -21,13 +25,125 @@ pub struct Config {
     #[envconfig(from = "DANMAKU_MAX_LENGTH", default = "50")]
     pub max_length: usize,
 
+    /// Danmaku min length after normalization, empty danmaku are always dropped
+    #[envconfig(from = "DANMAKU_MIN_LENGTH", default = "1")]
+    pub min_length: usize,
+
+    /// Forward images and faces to clients, only their text placeholders if disabled
+    #[envconfig(from = "DANMAKU_RICH_CONTENT", default = "true")]
+    pub rich_content: bool,
+
+    /// Max length of the replied text quoted before replies, -1 to disable quoting
+    #[envconfig(from = "DANMAKU_QUOTE_LENGTH", default = "-1")]
+    pub quote_length: i32,
+
+    /// Comma separated groups accepted from upstreams, every group is accepted if empty
+    #[envconfig(from = "DANMAKU_ALLOW_GROUPS", default = "")]
+    pub allow_groups: String,
+
+    /// Comma separated groups rejected from upstreams
+    #[envconfig(from = "DANMAKU_DENY_GROUPS", default = "")]
+    pub deny_groups: String,
+
+    /// Comma separated display modes accepted, every mode is accepted if empty
+    #[envconfig(from = "DANMAKU_ALLOW_MODES", default = "")]
+    pub allow_modes: String,
+
     /// Danmaku deduplication window (in seconds)
     #[envconfig(from = "DANMAKU_DEDUP_WINDOW", default = "-1")]
     pub dedup_window: i32,
 
-    /// Official QQBot Secret
-    #[envconfig(from = "DANMAKU_BOT_SECRET", default = "0")]
-    pub bot_secret: String,
+    /// Danmaku allowed per sender per minute in each group, -1 to disable
+    #[envconfig(from = "DANMAKU_SENDER_QUOTA", default = "-1")]
+    pub sender_quota: i32,
+
+    /// Burst size of the per-sender quota, 0 to use the quota itself
+    #[envconfig(from = "DANMAKU_SENDER_BURST", default = "0")]
+    pub sender_burst: u32,
+
+    /// Danmaku allowed per second in each group, -1 to disable
+    #[envconfig(from = "DANMAKU_GROUP_CAP", default = "-1")]
+    pub group_cap: i32,
+
+    /// Policy for danmaku over the group cap: drop_newest, sample or fresh_senders
+    #[envconfig(from = "DANMAKU_OVERFLOW_POLICY", default = "drop_newest")]
+    pub overflow_policy: OverflowPolicy,
+
+    /// Blacklist file path, the embedded blacklist is used if unset
+    #[envconfig(from = "DANMAKU_BLACKLIST_PATH")]
+    pub blacklist_path: Option<PathBuf>,
+
+    /// Mute list file path, mutes are kept in memory only if unset
+    #[envconfig(from = "DANMAKU_MUTE_PATH")]
+    pub mute_path: Option<PathBuf>,
+
+    /// Comma separated groups whose danmaku need manual approval
+    #[envconfig(from = "DANMAKU_REVIEW_GROUPS", default = "")]
+    pub review_groups: String,
+
+    /// How long danmaku wait for approval before being dropped (in seconds)
+    #[envconfig(from = "DANMAKU_REVIEW_TIMEOUT", default = "60")]
+    pub review_timeout: u64,
+
+    /// Group aliases file path, mapping public aliases to upstream groups
+    #[envconfig(from = "DANMAKU_ALIASES_PATH")]
+    pub aliases_path: Option<PathBuf>,
+
+    /// Number of danmaku kept per group for replay
+    #[envconfig(from = "DANMAKU_HISTORY_SIZE", default = "100")]
+    pub history_size: usize,
+
+    /// How long danmaku are kept for replay (in seconds)
+    #[envconfig(from = "DANMAKU_HISTORY_RETENTION", default = "300")]
+    pub history_retention: u64,
+
+    /// Danmaku archive database path, archiving is disabled if unset
+    #[envconfig(from = "DANMAKU_ARCHIVE_PATH")]
+    pub archive_path: Option<PathBuf>,
+
+    /// Danmaku archive retention (in days), -1 to keep forever
+    #[envconfig(from = "DANMAKU_ARCHIVE_RETENTION", default = "-1")]
+    pub archive_retention: i32,
+
+    /// Token required to read every group, clients are open if no read token is set
+    #[envconfig(from = "DANMAKU_READ_TOKEN")]
+    pub read_token: Option<String>,
+
+    /// Token required for raw upstreams, upstreams are open if no write token is set
+    #[envconfig(from = "DANMAKU_WRITE_TOKEN")]
+    pub write_token: Option<String>,
+
+    /// Tokens file path, holding per-group read tokens and write tokens
+    #[envconfig(from = "DANMAKU_TOKENS_PATH")]
+    pub tokens_path: Option<PathBuf>,
+
+    /// OneBot access token, OneBot connections are open if unset
+    #[envconfig(from = "DANMAKU_ONEBOT_TOKEN")]
+    pub onebot_token: Option<String>,
+
+    /// OneBot HTTP POST secret, unsigned events are accepted if unset
+    #[envconfig(from = "DANMAKU_ONEBOT_SECRET")]
+    pub onebot_secret: Option<String>,
+
+    /// OneBot forward WebSocket server to dial, as an alternative to reverse WebSocket
+    #[envconfig(from = "DANMAKU_ONEBOT_FORWARD_URL")]
+    pub onebot_forward_url: Option<String>,
+
+    /// Ignore messages sent by the OneBot account itself
+    #[envconfig(from = "DANMAKU_IGNORE_SELF", default = "true")]
+    pub ignore_self: bool,
+
+    /// Comma separated OneBot user ids whose messages are ignored, such as other bots
+    #[envconfig(from = "DANMAKU_IGNORE_USERS", default = "")]
+    pub ignore_users: String,
+
+    /// Comma separated prefixes of OneBot messages to ignore, such as bot commands
+    #[envconfig(from = "DANMAKU_IGNORE_PREFIXES", default = "")]
+    pub ignore_prefixes: String,
+
+    /// Official QQBot Secret, every webhook request is rejected if unset
+    #[envconfig(from = "DANMAKU_BOT_SECRET")]
+    pub bot_secret: Option<String>,
 }
 
 impl Config {
@@ -42,3 +158,40 @@ impl Config {
         config.clone()
     }
 }
+
+#[cfg(test)]
+impl Config {
+    /// Config from the given variables and defaults, independent of the environment
+    pub fn from_vars(vars: &[(&str, &str)]) -> Self {
+        let vars = vars
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<std::collections::HashMap<_, _>>();
+        Config::init_from_hashmap(&vars).expect("invalid test config")
+    }
+}
+
+/// Split a comma separated list
+pub fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// Poll a file and call `reload` whenever it changes
+pub async fn watch_file(path: PathBuf, reload: impl Fn()) {
+    let modified = || std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+    let mut last = modified();
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        let current = modified();
+        if current.is_none() || current == last {
+            continue;
+        }
+        last = current;
+        reload();
+    }
+}
@@ -1,32 +1,231 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use futures::StreamExt;
 use futures_util::SinkExt;
+use poem::http::{HeaderMap, StatusCode};
 use poem::web::websocket::{Message, WebSocket};
-use poem::web::{Data, Path, RemoteAddr};
-use poem::{handler, IntoResponse};
-use ring_channel::RingSender;
+use poem::web::{Data, Path, Query, RemoteAddr};
+use poem::{handler, IntoResponse, Response};
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 use tokio::sync::broadcast;
 use tokio::sync::broadcast::error::RecvError;
+use ulid::Ulid;
 
-use crate::config::Config;
+use crate::alias::Aliases;
+use crate::auth::{bearer, TokenQuery, Tokens};
+use crate::config::{split_list, Config};
+use crate::history::{History, Replay};
+use crate::middleware::Inlet;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Danmaku {
+    /// Plain text, with placeholders for non-text content
     pub text: Arc<str>,
+    /// Rich content, only set if the danmaku has more than text
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub content: Vec<Content>,
     pub color: Option<Arc<str>>,
     pub size: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<Mode>,
     pub sender: Option<Arc<str>>,
+    /// Danmaku this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Box<ReplyTo>>,
+    /// Stable upstream identity of the sender, not exposed to clients
+    #[serde(default, skip_serializing)]
+    pub sender_id: Option<Arc<str>>,
+}
+
+/// Replied danmaku, for clients that draw threads
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct ReplyTo {
+    pub id: Ulid,
+    pub sender: Option<Arc<str>>,
+    /// Text of the replied danmaku, without its own quote
+    pub text: Arc<str>,
+}
+
+/// Segment of rich danmaku content
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Content {
+    Text {
+        text: Arc<str>,
+    },
+    /// Picture or sticker
+    Image {
+        url: Arc<str>,
+        /// Short description such as `[开心]`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        summary: Option<Arc<str>>,
+    },
+    /// Built-in face of the upstream
+    Face {
+        id: SmolStr,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<SmolStr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        emoji: Option<SmolStr>,
+    },
+}
+
+impl Danmaku {
+    /// Identity of the sender for per-sender limits, the display name if the upstream has no id
+    pub fn sender_key(&self) -> Option<&Arc<str>> {
+        self.sender_id.as_ref().or(self.sender.as_ref())
+    }
+}
+
+impl Content {
+    /// Placeholder for text-only clients
+    pub fn to_text(&self) -> Cow<'_, str> {
+        match self {
+            Content::Text { text } => text.as_ref().into(),
+            Content::Image {
+                summary: Some(summary),
+                ..
+            } => summary.as_ref().into(),
+            Content::Image { .. } => "[图片]".into(),
+            Content::Face {
+                emoji: Some(emoji), ..
+            } => emoji.as_str().into(),
+            Content::Face {
+                name: Some(name), ..
+            } => format!("[{}]", name).into(),
+            Content::Face { .. } => "[表情]".into(),
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        matches!(self, Content::Text { .. })
+    }
+}
+
+/// How a danmaku is displayed, scrolling if unset
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mode {
+    Scroll,
+    Top,
+    Bottom,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Scroll => "scroll",
+            Mode::Top => "top",
+            Mode::Bottom => "bottom",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "scroll" => Ok(Mode::Scroll),
+            "top" => Ok(Mode::Top),
+            "bottom" => Ok(Mode::Bottom),
+            _ => Err(eyre::eyre!("unknown mode: {}", s)),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct DanmakuPacket {
     pub group: SmolStr,
     pub danmaku: Danmaku,
+    /// Upstream the packet comes from, set by the server
+    #[serde(skip)]
+    pub source: Upstream,
+    /// Unique packet id, set by the server
+    #[serde(default, skip_deserializing)]
+    pub id: Option<Ulid>,
+    /// Receive time in milliseconds since unix epoch, set by the server
+    #[serde(default, skip_deserializing)]
+    pub received_at: Option<i64>,
+    /// Original send time in milliseconds since unix epoch, reported by the upstream
+    #[serde(default)]
+    pub sent_at: Option<i64>,
+    /// Message id in the upstream, used to follow recalls
+    #[serde(skip)]
+    pub message_id: Option<SmolStr>,
+    /// Upstream message id of the message replied to
+    #[serde(skip)]
+    pub in_reply_to: Option<SmolStr>,
+}
+
+/// Event from upstreams to the middleware task
+// almost every event is a danmaku, boxing it would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum UpstreamEvent {
+    Danmaku(DanmakuPacket),
+    /// A message was recalled in the upstream
+    Recall {
+        group: SmolStr,
+        message_id: SmolStr,
+    },
+}
+
+/// Event from the middleware task to clients
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum DownstreamEvent {
+    Danmaku(DanmakuPacket),
+    /// A delivered danmaku was retracted
+    Delete {
+        group: SmolStr,
+        id: Ulid,
+    },
+}
+
+pub fn unix_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Danmaku upstream kind
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Upstream {
+    OneBot,
+    Webhook,
+    #[default]
+    Raw,
+}
+
+impl Upstream {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Upstream::OneBot => "one_bot",
+            Upstream::Webhook => "webhook",
+            Upstream::Raw => "raw",
+        }
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "one_bot" => Ok(Upstream::OneBot),
+            "webhook" => Ok(Upstream::Webhook),
+            "raw" => Ok(Upstream::Raw),
+            _ => Err(eyre::eyre!("unknown upstream: {}", s)),
+        }
+    }
 }
 
 impl Display for Danmaku {
@@ -35,7 +234,7 @@ impl Display for Danmaku {
             write!(f, "{}: ", sender)?;
         }
         write!(f, "{}", self.text)?;
-        if self.color.is_some() || self.size.is_some() {
+        if self.color.is_some() || self.size.is_some() || self.mode.is_some() {
             write!(f, " [")?;
             if let Some(color) = &self.color {
                 write!(f, "color={};", color)?;
@@ -43,36 +242,129 @@ impl Display for Danmaku {
             if let Some(size) = self.size {
                 write!(f, "size={};", size)?;
             }
+            if let Some(mode) = self.mode {
+                write!(f, "mode={};", mode.as_str())?;
+            }
             write!(f, "]")?;
         }
         Ok(())
     }
 }
 
+/// Event sent to clients, tagged with the public name of the group it comes from
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientEvent<'a> {
+    Danmaku(ClientDanmaku<'a>),
+    Delete { group: SmolStr, id: Ulid },
+}
+
+#[derive(Serialize, Debug)]
+struct ClientDanmaku<'a> {
+    #[serde(flatten)]
+    danmaku: &'a Danmaku,
+    group: SmolStr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Ulid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    received_at: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sent_at: Option<i64>,
+}
+
+/// Extra groups to subscribe in the same connection
+#[derive(Deserialize, Debug, Default)]
+pub struct GroupsQuery {
+    /// Comma separated group list
+    groups: Option<String>,
+}
+
 #[handler]
-#[tracing::instrument(skip(ws))]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(skip(ws, headers, auth, history, tokens, aliases))]
 pub async fn client(
     ws: WebSocket,
     RemoteAddr(peer): &RemoteAddr,
-    Path(group): Path<SmolStr>,
-    Data(source): Data<&Arc<broadcast::Receiver<DanmakuPacket>>>,
-) -> impl IntoResponse {
+    headers: &HeaderMap,
+    Path(id): Path<SmolStr>,
+    Query(extra): Query<GroupsQuery>,
+    Query(replay): Query<Replay>,
+    Query(auth): Query<TokenQuery>,
+    Data(source): Data<&Arc<broadcast::Receiver<DownstreamEvent>>>,
+    Data(history): Data<&Arc<History>>,
+    Data(tokens): Data<&Arc<Tokens>>,
+    Data(aliases): Data<&Arc<Aliases>>,
+) -> Response {
     let peer = peer.clone();
-    tracing::info!("connection from {} to group {}", peer, &group);
+    let token = bearer(headers).or(auth.token.as_deref());
+
+    // `/danmaku/a+b` and `?groups=c,d` subscribe to several groups at once
+    let ids = id
+        .split('+')
+        .chain(split_list(extra.groups.as_deref().unwrap_or_default()))
+        .filter(|id| !id.is_empty())
+        .map(SmolStr::from)
+        .collect::<Vec<_>>();
+    if let Some(id) = ids.iter().find(|id| !tokens.check_read(id, token)) {
+        tracing::warn!("rejected connection from {} to group {}", peer, id);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
 
+    tracing::info!("connection from {} to groups {:?}", peer, ids);
+
+    // subscribe before taking the snapshot so that nothing falls in between,
+    // danmaku in both are skipped when they arrive live
     let mut source = source.resubscribe();
+    let groups = ids
+        .iter()
+        .flat_map(|id| aliases.resolve(id))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let replay = history.replay(&groups, &replay);
+    let mut replayed = replay
+        .iter()
+        .filter_map(|packet| packet.id)
+        .collect::<HashSet<_>>();
+    // danmaku are tagged with the requested id rather than the upstream group, so that
+    // aliases don't reveal group numbers; they are resolved per danmaku so that
+    // reloading them applies to live connections
+    let aliases = aliases.clone();
+    let encode = move |event: &DownstreamEvent| {
+        let event = match event {
+            DownstreamEvent::Danmaku(packet) => ClientEvent::Danmaku(ClientDanmaku {
+                danmaku: &packet.danmaku,
+                group: aliases.origin(&ids, &packet.group)?,
+                id: packet.id,
+                received_at: packet.received_at,
+                sent_at: packet.sent_at,
+            }),
+            DownstreamEvent::Delete { group, id } => ClientEvent::Delete {
+                group: aliases.origin(&ids, group)?,
+                id: *id,
+            },
+        };
+        serde_json::to_string(&event).ok()
+    };
     ws.on_upgrade(move |mut socket| async move {
+        for packet in replay {
+            if let Some(danmaku) = encode(&DownstreamEvent::Danmaku(packet)) {
+                let _ = socket.send(Message::Text(danmaku)).await;
+            }
+        }
+
         let mut ping = tokio::time::interval(Duration::from_secs(30));
         loop {
             tokio::select! {
                 // From upstream
-                packet = source.recv() => {
-                    match packet {
-                        Ok(packet) => {
-                            if packet.group != group { continue; }
-                            if let Ok(danmaku) = serde_json::to_string(&packet.danmaku) {
-                                let _ = socket.send(Message::Text(danmaku)).await;
-                                tracing::debug!("{} -> {}", packet.group, packet.danmaku);
+                event = source.recv() => {
+                    match event {
+                        Ok(DownstreamEvent::Danmaku(packet))
+                            if packet.id.is_some_and(|id| replayed.remove(&id)) => continue,
+                        Ok(event) => {
+                            if let Some(message) = encode(&event) {
+                                let _ = socket.send(Message::Text(message)).await;
+                                tracing::debug!("-> {:?}", event);
                             }
                         }
                         Err(RecvError::Lagged(_)) => continue,
@@ -110,16 +402,24 @@ pub async fn client(
             tracing::error!("failed to close connection: {}", e);
         }
     })
+    .into_response()
 }
 
 #[handler]
-#[tracing::instrument(skip(ws, sink))]
+#[tracing::instrument(skip(ws, headers, auth, sink, tokens))]
 pub async fn upstream(
     ws: WebSocket,
     RemoteAddr(peer): &RemoteAddr,
-    Data(sink): Data<&RingSender<DanmakuPacket>>,
-) -> impl IntoResponse {
+    headers: &HeaderMap,
+    Query(auth): Query<TokenQuery>,
+    Data(sink): Data<&Inlet>,
+    Data(tokens): Data<&Arc<Tokens>>,
+) -> Response {
     let peer = peer.clone();
+    if !tokens.check_write(bearer(headers).or(auth.token.as_deref())) {
+        tracing::warn!("rejected upstream connection from {}", peer);
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
     tracing::info!("connection from {}", peer);
 
     let sink = sink.clone();
@@ -136,7 +436,7 @@ pub async fn upstream(
                         Message::Text(msg) => {
                             if let Ok(packet) = serde_json::from_str::<DanmakuPacket>(&msg) {
                                 if packet.danmaku.text.chars().count() > config.max_length { continue; }
-                                sink.send(packet).expect("all middleware tasks are gone");
+                                sink.send(packet);
                             }
                         }
                         Message::Ping(payload) => {
@@ -165,4 +465,155 @@ pub async fn upstream(
             tracing::error!("failed to close connection: {}", e);
         }
     })
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::listener::TcpAcceptor;
+    use poem::{get, EndpointExt, Route, Server};
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::tungstenite::Error;
+
+    use super::*;
+
+    #[test]
+    fn serializes_client_events() {
+        let id: Ulid = "01JAAAAAAAAAAAAAAAAAAAAAAA".parse().unwrap();
+        let mut danmaku: Danmaku =
+            serde_json::from_value(serde_json::json!({ "text": "hi" })).unwrap();
+        danmaku.sender_id = Some("42".into());
+        let event = ClientEvent::Danmaku(ClientDanmaku {
+            danmaku: &danmaku,
+            group: "stage".into(),
+            id: None,
+            received_at: None,
+            sent_at: None,
+        });
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "danmaku",
+                "text": "hi",
+                "color": null,
+                "size": null,
+                "sender": null,
+                "group": "stage",
+            })
+        );
+
+        danmaku.content = vec![Content::Face {
+            id: "76".into(),
+            name: None,
+            emoji: Some("👍".into()),
+        }];
+        danmaku.color = Some("red".into());
+        danmaku.size = Some(40.0);
+        danmaku.mode = Some(Mode::Top);
+        danmaku.sender = Some("Alice".into());
+        danmaku.reply_to = Some(Box::new(ReplyTo {
+            id,
+            sender: None,
+            text: "yo".into(),
+        }));
+        let event = ClientEvent::Danmaku(ClientDanmaku {
+            danmaku: &danmaku,
+            group: "stage".into(),
+            id: Some(id),
+            received_at: Some(2000),
+            sent_at: Some(1000),
+        });
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "type": "danmaku",
+                "text": "hi",
+                "content": [{ "type": "face", "id": "76", "emoji": "👍" }],
+                "color": "red",
+                "size": 40.0,
+                "mode": "top",
+                "sender": "Alice",
+                "reply_to": { "id": "01JAAAAAAAAAAAAAAAAAAAAAAA", "sender": null, "text": "yo" },
+                "group": "stage",
+                "id": "01JAAAAAAAAAAAAAAAAAAAAAAA",
+                "received_at": 2000,
+                "sent_at": 1000,
+            })
+        );
+
+        let event = ClientEvent::Delete {
+            group: "stage".into(),
+            id,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({ "type": "delete", "group": "stage", "id": "01JAAAAAAAAAAAAAAAAAAAAAAA" })
+        );
+    }
+
+    /// Serve `client` on a random port, reading groups `1` and `2` with tokens `one` and `two`
+    async fn serve() -> (std::net::SocketAddr, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("danmaku-tokens-{}.json", Ulid::new()));
+        std::fs::write(&path, r#"{"read": {"1": ["one"], "2": ["two"]}}"#).unwrap();
+        let config = Config::from_vars(&[("DANMAKU_TOKENS_PATH", path.to_str().unwrap())]);
+        let (_, source) = broadcast::channel::<DownstreamEvent>(1);
+        let app = Route::new().at(
+            "/danmaku/:id",
+            get(client
+                .data(Arc::new(source))
+                .data(Arc::new(History::from_config(&config)))
+                .data(Arc::new(Tokens::from_config(&config).unwrap()))
+                .data(Arc::new(Aliases::from_config(&config)))),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = TcpAcceptor::from_tokio(listener).unwrap();
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+        (addr, path)
+    }
+
+    /// Connect and return the HTTP status of the handshake
+    async fn connect(addr: std::net::SocketAddr, path: &str, bearer: Option<&str>) -> u16 {
+        let mut request = format!("ws://{addr}{path}").into_client_request().unwrap();
+        if let Some(token) = bearer {
+            let value = format!("Bearer {token}").parse().unwrap();
+            request.headers_mut().insert("Authorization", value);
+        }
+        match tokio_tungstenite::connect_async(request).await {
+            Ok((_, response)) => response.status().as_u16(),
+            Err(Error::Http(response)) => response.status().as_u16(),
+            Err(e) => panic!("failed to connect: {e}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn client_checks_every_group() {
+        let (addr, path) = serve().await;
+        assert_eq!(connect(addr, "/danmaku/1?token=one", None).await, 101);
+        assert_eq!(connect(addr, "/danmaku/1", None).await, 401);
+        assert_eq!(connect(addr, "/danmaku/1?token=two", None).await, 401);
+        // a token for one group doesn't open the others
+        assert_eq!(connect(addr, "/danmaku/1+2?token=one", None).await, 401);
+        assert_eq!(connect(addr, "/danmaku/2+1?token=two", None).await, 401);
+        assert_eq!(
+            connect(addr, "/danmaku/1?groups=2&token=one", None).await,
+            401
+        );
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn client_prefers_bearer_token() {
+        let (addr, path) = serve().await;
+        assert_eq!(connect(addr, "/danmaku/1", Some("one")).await, 101);
+        assert_eq!(
+            connect(addr, "/danmaku/1?token=two", Some("one")).await,
+            101
+        );
+        assert_eq!(
+            connect(addr, "/danmaku/1?token=one", Some("two")).await,
+            401
+        );
+        std::fs::remove_file(path).ok();
+    }
 }
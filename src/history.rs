@@ -0,0 +1,286 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use smol_str::SmolStr;
+use ulid::Ulid;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::danmaku::DanmakuPacket;
+
+/// Number of delivered danmaku kept for recall lookup
+const RECENT_SIZE: usize = 1024;
+
+/// Replay request from a late-joining client
+#[derive(Deserialize, Debug, Default)]
+pub struct Replay {
+    /// Replay the last N danmaku
+    pub last: Option<usize>,
+    /// Replay danmaku from the last T seconds
+    pub since: Option<u64>,
+}
+
+/// Bounded per-group danmaku history
+pub struct History<C = SystemClock> {
+    size: usize,
+    retention: Duration,
+    clock: C,
+    groups: Mutex<HashMap<SmolStr, VecDeque<(Instant, DanmakuPacket)>>>,
+}
+
+impl History {
+    pub fn from_config(config: &Config) -> Self {
+        Self::new(
+            config.history_size,
+            Duration::from_secs(config.history_retention),
+            SystemClock,
+        )
+    }
+}
+
+impl<C: Clock> History<C> {
+    pub(crate) fn new(size: usize, retention: Duration, clock: C) -> Self {
+        Self {
+            size,
+            retention,
+            clock,
+            groups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a danmaku that has passed the middleware chain
+    pub fn push(&self, packet: &DanmakuPacket) {
+        if self.size == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        let mut groups = self.groups.lock().unwrap();
+        let history = groups.entry(packet.group.clone()).or_default();
+        while history.len() >= self.size {
+            history.pop_front();
+        }
+        history.push_back((now, packet.clone()));
+
+        // evict expired entries of every group, so idle groups do not pin memory
+        groups.retain(|_, history| {
+            while let Some((time, _)) = history.front() {
+                if now.duration_since(*time) <= self.retention {
+                    break;
+                }
+                history.pop_front();
+            }
+            !history.is_empty()
+        });
+    }
+
+    /// Remove a danmaku so that it is not replayed anymore, returns its group if found
+    pub fn remove(&self, id: Ulid) -> Option<SmolStr> {
+        let mut groups = self.groups.lock().unwrap();
+        let (group, history) = groups
+            .iter_mut()
+            .find(|(_, history)| history.iter().any(|(_, packet)| packet.id == Some(id)))?;
+        history.retain(|(_, packet)| packet.id != Some(id));
+        Some(group.clone())
+    }
+
+    /// Collect danmaku of groups matching the replay request, oldest first
+    pub fn replay(&self, groups: &[SmolStr], replay: &Replay) -> Vec<DanmakuPacket> {
+        if replay.last.is_none() && replay.since.is_none() {
+            return vec![];
+        }
+
+        let now = self.clock.now();
+        let since = replay
+            .since
+            .map(Duration::from_secs)
+            .unwrap_or(self.retention)
+            .min(self.retention);
+        let last = replay.last.unwrap_or(self.size);
+
+        let history = self.groups.lock().unwrap();
+        let mut packets = groups
+            .iter()
+            .filter_map(|group| history.get(group))
+            .flat_map(|history| {
+                let start = history
+                    .iter()
+                    .rposition(|(time, _)| now.duration_since(*time) > since)
+                    .map_or(0, |i| i + 1)
+                    .max(history.len().saturating_sub(last));
+                history.range(start..)
+            })
+            .collect::<Vec<_>>();
+        // stable, so danmaku pushed at the same instant keep their order
+        packets.sort_by_key(|(time, _)| *time);
+        let skip = packets.len().saturating_sub(last);
+        packets
+            .into_iter()
+            .skip(skip)
+            .map(|(_, packet)| packet.clone())
+            .collect()
+    }
+}
+
+/// Recently delivered danmaku of every group, to map upstream message ids to danmaku
+///
+/// Kept apart from the history so that recalls and replies work whatever the replay settings.
+#[derive(Default)]
+pub struct Recent(Mutex<VecDeque<DanmakuPacket>>);
+
+impl Recent {
+    pub fn push(&self, packet: &DanmakuPacket) {
+        let mut recent = self.0.lock().unwrap();
+        if recent.len() >= RECENT_SIZE {
+            recent.pop_front();
+        }
+        recent.push_back(packet.clone());
+    }
+
+    /// Find a delivered danmaku by its upstream message id
+    pub fn find(&self, group: &str, message_id: &str) -> Option<DanmakuPacket> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|packet| {
+                packet.group == group && packet.message_id.as_deref() == Some(message_id)
+            })
+            .cloned()
+    }
+
+    /// Forget a danmaku, returns its group if found
+    pub fn remove(&self, id: Ulid) -> Option<SmolStr> {
+        let mut recent = self.0.lock().unwrap();
+        let index = recent.iter().position(|packet| packet.id == Some(id))?;
+        recent.remove(index).map(|packet| packet.group)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    fn packet(group: &str, text: &str) -> DanmakuPacket {
+        let mut packet: DanmakuPacket = serde_json::from_value(
+            serde_json::json!({ "group": group, "danmaku": { "text": text } }),
+        )
+        .unwrap();
+        packet.id = Some(Ulid::new());
+        packet
+    }
+
+    fn texts(packets: &[DanmakuPacket]) -> Vec<&str> {
+        packets.iter().map(|packet| &*packet.danmaku.text).collect()
+    }
+
+    fn replay(last: Option<usize>, since: Option<u64>) -> Replay {
+        Replay { last, since }
+    }
+
+    #[test]
+    fn replays_last_n_and_last_t() {
+        let clock = MockClock::new();
+        let history = History::new(10, Duration::from_secs(60), clock.clone());
+        for text in ["a", "b", "c", "d"] {
+            history.push(&packet("1", text));
+            history.push(&packet("2", &text.to_uppercase()));
+            clock.advance(Duration::from_secs(1));
+        }
+
+        let groups = ["1".into()];
+        assert!(history.replay(&groups, &Replay::default()).is_empty());
+        assert_eq!(
+            texts(&history.replay(&groups, &replay(Some(2), None))),
+            ["c", "d"]
+        );
+        assert_eq!(
+            texts(&history.replay(&groups, &replay(None, Some(2)))),
+            ["c", "d"]
+        );
+        assert_eq!(
+            texts(&history.replay(&groups, &replay(Some(1), Some(3)))),
+            ["d"]
+        );
+
+        // several groups are merged in time order
+        let groups = ["1".into(), "2".into()];
+        assert_eq!(
+            texts(&history.replay(&groups, &replay(Some(3), None))),
+            ["C", "d", "D"]
+        );
+    }
+
+    #[test]
+    fn evicts_by_size_and_retention() {
+        let clock = MockClock::new();
+        let history = History::new(2, Duration::from_secs(10), clock.clone());
+        for text in ["a", "b", "c"] {
+            history.push(&packet("1", text));
+        }
+        let groups = ["1".into()];
+        let all = replay(Some(10), None);
+        assert_eq!(texts(&history.replay(&groups, &all)), ["b", "c"]);
+
+        // expired danmaku are not replayed even if asked for a longer period
+        clock.advance(Duration::from_secs(11));
+        assert!(history.replay(&groups, &replay(None, Some(60))).is_empty());
+
+        // and are evicted on the next push to any group
+        history.push(&packet("2", "d"));
+        assert!(history.groups.lock().unwrap().get("1").is_none());
+    }
+
+    #[test]
+    fn removes_by_id() {
+        let history = History::new(10, Duration::from_secs(10), MockClock::new());
+        let removed = packet("1", "a");
+        history.push(&removed);
+        history.push(&packet("1", "b"));
+
+        assert_eq!(history.remove(removed.id.unwrap()).as_deref(), Some("1"));
+        assert!(history.remove(removed.id.unwrap()).is_none());
+        assert_eq!(
+            texts(&history.replay(&["1".into()], &replay(Some(10), None))),
+            ["b"]
+        );
+    }
+
+    #[test]
+    fn recent_finds_and_removes_by_message_id() {
+        let recent = Recent::default();
+        let mut recalled = packet("1", "a");
+        recalled.message_id = Some("100".into());
+        recent.push(&recalled);
+        recent.push(&packet("1", "b"));
+
+        assert!(recent.find("2", "100").is_none());
+        let found = recent.find("1", "100").unwrap();
+        assert_eq!(found.id, recalled.id);
+        assert_eq!(recent.remove(found.id.unwrap()).as_deref(), Some("1"));
+        assert!(recent.find("1", "100").is_none());
+        assert!(recent.remove(found.id.unwrap()).is_none());
+
+        // bounded regardless of the history settings
+        recent.push(&recalled);
+        for _ in 0..RECENT_SIZE - 1 {
+            recent.push(&packet("1", "c"));
+        }
+        assert!(recent.find("1", "100").is_some());
+        recent.push(&packet("1", "c"));
+        assert!(recent.find("1", "100").is_none());
+    }
+
+    #[test]
+    fn disabled_history_keeps_nothing() {
+        let history = History::new(0, Duration::from_secs(10), MockClock::new());
+        history.push(&packet("1", "a"));
+        assert!(history
+            .replay(&["1".into()], &replay(Some(10), None))
+            .is_empty());
+    }
+}
@@ -10,13 +10,26 @@ use ring_channel::ring_channel;
 use tokio::sync::broadcast;
 use tracing_subscriber::EnvFilter;
 
-use crate::danmaku::DanmakuPacket;
-use crate::middleware::run_middleware;
-
+use crate::alias::Aliases;
+use crate::archive::Archive;
+use crate::auth::Tokens;
+use crate::danmaku::{DownstreamEvent, UpstreamEvent};
+use crate::history::{History, Recent};
+use crate::middleware::{run_middleware, GroupFilter, Inlet, Moderation, Outlet};
+use crate::review::ReviewQueue;
+
+mod admin;
+mod alias;
+mod archive;
+mod auth;
+mod clock;
 mod config;
 mod danmaku;
+mod history;
 mod middleware;
+mod normalize;
 mod onebot;
+mod review;
 mod webhook;
 
 #[tokio::main]
@@ -28,6 +41,7 @@ async fn main() -> Result<()> {
         .init();
 
     let config = config::Config::load();
+    let tokens = Arc::new(Tokens::from_config(&config)?);
 
     // gracefully shutdown on ctrl-c or SIGTERM
     tokio::spawn(async move {
@@ -48,9 +62,39 @@ async fn main() -> Result<()> {
 
     // server
     // upstream -|ring_channel|-> middlewares -|broadcast|-> downstream
-    let (source, middle) = ring_channel::<DanmakuPacket>(32.try_into().unwrap());
-    let sink = broadcast::channel::<DanmakuPacket>(32).0;
-    tokio::spawn(run_middleware(middle, sink.clone()));
+    let (source, middle) = ring_channel::<UpstreamEvent>(32.try_into().unwrap());
+    let source = Inlet::new(source, GroupFilter::from_config(&config));
+    let sink = broadcast::channel::<DownstreamEvent>(32).0;
+    let history = Arc::new(History::from_config(&config));
+    let archive = Archive::from_config(&config)?.map(Arc::new);
+    let outlet = Arc::new(Outlet {
+        sink: sink.clone(),
+        history: history.clone(),
+        archive: archive.clone(),
+        recent: Recent::default(),
+    });
+    let aliases = Arc::new(Aliases::from_config(&config));
+    tokio::spawn(aliases.clone().watch());
+    let moderation = Arc::new(Moderation::from_config(&config));
+    tokio::spawn(moderation.blacklist.clone().watch());
+    let review = Arc::new(ReviewQueue::from_config(&config, outlet.clone()));
+    tokio::spawn(review.clone().expire());
+    tokio::spawn(run_middleware(
+        middle,
+        outlet.clone(),
+        moderation.clone(),
+        review.clone(),
+    ));
+
+    let ignore = Arc::new(onebot::IgnoreRules::from_config(&config));
+    if let Some(url) = &config.onebot_forward_url {
+        tokio::spawn(onebot::forward(
+            url.clone(),
+            config.onebot_token.clone(),
+            source.clone(),
+            ignore.clone(),
+        ));
+    }
 
     // public server
     let app = Route::new()
@@ -61,7 +105,10 @@ async fn main() -> Result<()> {
             "/danmaku/:id",
             get(danmaku::client
                 .data(source.clone())
-                .data(Arc::new(sink.subscribe()))),
+                .data(Arc::new(sink.subscribe()))
+                .data(history)
+                .data(tokens.clone())
+                .data(aliases.clone())),
         )
         .with(NormalizePath::new(TrailingSlash::Trim));
 
@@ -73,11 +120,33 @@ async fn main() -> Result<()> {
     let public = Server::new(TcpListener::bind((config.listen, config.port))).run(app);
 
     // private server
-    let app = Route::new()
-        .at("/onebot", get(onebot::onebot.data(source.clone())))
+    let mut app = Route::new()
+        .at(
+            "/onebot",
+            get(onebot::onebot.data(source.clone()).data(ignore.clone())),
+        )
+        .at(
+            "/onebot/http",
+            post(onebot::onebot_http.data(source.clone()).data(ignore)),
+        )
         .at("/webhook", post(webhook::webhook.data(source.clone())))
-        .at("/danmaku", get(danmaku::upstream.data(source.clone())))
-        .with(NormalizePath::new(TrailingSlash::Trim));
+        .at(
+            "/danmaku",
+            get(danmaku::upstream.data(source.clone()).data(tokens)),
+        )
+        .at("/review", get(review::moderator.data(review.clone())))
+        .at(
+            "/blacklist/reload",
+            post(admin::reload_blacklist.data(moderation.clone())),
+        )
+        .nest(
+            "/admin",
+            admin::route(moderation, review, outlet, source.filter.clone(), aliases),
+        );
+    if let Some(archive) = archive {
+        app = app.at("/archive/:id", get(archive::export.data(archive)));
+    }
+    let app = app.with(NormalizePath::new(TrailingSlash::Trim));
 
     tracing::info!(
         "private listening on {}:{}",
@@ -1,16 +1,33 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 use std::vec;
 
+use eyre::Result;
 use futures::StreamExt;
 use governor::{DefaultKeyedRateLimiter, Quota};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use regex::RegexSet;
-use ring_channel::RingReceiver;
+use ring_channel::{RingReceiver, RingSender};
+use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
 use tokio::sync::broadcast;
+use ulid::Ulid;
 
-use crate::config::Config;
-use crate::danmaku::DanmakuPacket;
+use crate::archive::Archive;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{split_list, watch_file, Config};
+use crate::danmaku::{
+    unix_millis, Content, DanmakuPacket, DownstreamEvent, Mode, ReplyTo, UpstreamEvent,
+};
+use crate::history::{History, Recent};
+use crate::normalize::{clean, normalize};
+use crate::review::ReviewQueue;
 
 /// Danmaku Middleware
 trait Middleware {
@@ -46,6 +63,48 @@ impl Middleware for MiddlewareChain {
     }
 }
 
+/// Clean up danmaku text and drop danmaku that are too short
+struct Normalize {
+    min_length: usize,
+    /// Keep images and faces, or degrade them to their placeholders in the text
+    rich_content: bool,
+}
+
+impl Normalize {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            min_length: config.min_length.max(1),
+            rich_content: config.rich_content,
+        }
+    }
+}
+
+impl Middleware for Normalize {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, mut packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        let danmaku = &mut packet.danmaku;
+        danmaku.text = normalize(&danmaku.text).into();
+        if danmaku.text.chars().count() < self.min_length {
+            tracing::info!("drop too short: {:?}", danmaku.text);
+            return None;
+        }
+        if !self.rich_content {
+            danmaku.content.clear();
+        }
+        for content in &mut danmaku.content {
+            if let Content::Text { text } = content {
+                *text = clean(text).into();
+            }
+        }
+        danmaku.content.retain(|content| match content {
+            Content::Text { text } => !text.is_empty(),
+            _ => true,
+        });
+
+        Some(packet)
+    }
+}
+
 /// Display incoming danmaku to log
 struct Echo;
 
@@ -58,50 +117,474 @@ impl Middleware for Echo {
 }
 
 /// Deduplicate danmaku coming within a time window
-struct Dedup(DefaultKeyedRateLimiter<(SmolStr, Arc<str>)>);
+struct Dedup {
+    moderation: Arc<Moderation>,
+    window: i32,
+    limiter: Option<DefaultKeyedRateLimiter<(SmolStr, Arc<str>)>>,
+}
 
 impl Dedup {
-    fn from_config(config: &Config) -> Option<Self> {
-        if config.dedup_window <= 0 {
-            return None;
+    fn new(moderation: Arc<Moderation>) -> Self {
+        Self {
+            moderation,
+            window: 0,
+            limiter: None,
         }
-        let duration = Duration::from_secs(config.dedup_window as u64);
-        Some(Self(DefaultKeyedRateLimiter::keyed(
-            Quota::with_period(duration).expect("invalid quota"),
-        )))
+    }
+}
+
+impl Dedup {
+    /// Text of a danmaku along with the images and faces behind its placeholders
+    fn key(packet: &DanmakuPacket) -> (SmolStr, Arc<str>) {
+        let danmaku = &packet.danmaku;
+        let mut key = danmaku.text.to_string();
+        for content in &danmaku.content {
+            match content {
+                Content::Text { .. } => {}
+                Content::Image { url, .. } => {
+                    key.push_str("\0image:");
+                    key.push_str(url);
+                }
+                Content::Face { id, .. } => {
+                    key.push_str("\0face:");
+                    key.push_str(id);
+                }
+            }
+        }
+        (packet.group.clone(), key.into())
     }
 }
 
 impl Middleware for Dedup {
     #[tracing::instrument(skip(self))]
     fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        // rebuild the limiter when the window is changed at runtime
+        let window = self.moderation.dedup_window();
+        if window != self.window {
+            self.window = window;
+            self.limiter = (window > 0).then(|| {
+                let duration = Duration::from_secs(window as u64);
+                DefaultKeyedRateLimiter::keyed(Quota::with_period(duration).expect("invalid quota"))
+            });
+        }
+        let Some(limiter) = &self.limiter else {
+            return Some(packet);
+        };
+
+        if limiter.check_key(&Self::key(&packet)).is_ok() {
+            Some(packet)
+        } else {
+            tracing::info!("drop duplicate: {}", packet.danmaku);
+            None
+        }
+    }
+}
+
+/// Drop danmaku from muted senders
+struct Mute(Arc<Moderation>);
+
+impl Middleware for Mute {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        if let Some(sender_id) = &packet.danmaku.sender_id {
+            if self.0.mutes.is_muted(&packet.group, sender_id) {
+                tracing::info!("drop muted: {}", packet.danmaku);
+                return None;
+            }
+        }
+        Some(packet)
+    }
+}
+
+/// Rate-limit danmaku per sender in each group
+struct SenderLimit(DefaultKeyedRateLimiter<(SmolStr, Arc<str>)>);
+
+impl SenderLimit {
+    fn new(quota: Quota) -> Self {
+        Self(DefaultKeyedRateLimiter::keyed(quota))
+    }
+
+    fn from_config(config: &Config) -> Option<Self> {
+        let per_minute = NonZeroU32::new(config.sender_quota.try_into().ok()?)?;
+        let burst = NonZeroU32::new(config.sender_burst).unwrap_or(per_minute);
+        Some(Self::new(Quota::per_minute(per_minute).allow_burst(burst)))
+    }
+}
+
+impl Middleware for SenderLimit {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        let Some(sender) = packet.danmaku.sender_key() else {
+            return Some(packet);
+        };
         if self
             .0
-            .check_key(&(packet.group.clone(), packet.danmaku.text.clone()))
+            .check_key(&(packet.group.clone(), sender.clone()))
             .is_ok()
         {
             Some(packet)
         } else {
-            tracing::info!("drop duplicate: {}", packet.danmaku);
+            tracing::info!("drop rate limited: {}", packet.danmaku);
             None
         }
     }
 }
 
-/// Filter danmaku by regex
-struct RegexFilter(RegexSet);
+/// Policy for danmaku exceeding the per-group throughput cap
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop everything over the cap
+    #[default]
+    DropNewest,
+    /// Spread the cap evenly over the incoming danmaku by random sampling
+    Sample,
+    /// Reserve half of the cap for senders who have not posted recently
+    FreshSenders,
+}
 
-impl RegexFilter {
-    fn new() -> Self {
-        const BLACKLIST: &str = include_str!("../assets/blacklist.txt");
-        Self(RegexSet::new(BLACKLIST.trim().lines()).expect("invalid regex"))
+impl FromStr for OverflowPolicy {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "sample" => Ok(OverflowPolicy::Sample),
+            "fresh_senders" => Ok(OverflowPolicy::FreshSenders),
+            _ => Err(eyre::eyre!("unknown overflow policy: {}", s)),
+        }
+    }
+}
+
+/// Throughput of a group in the current second
+struct Window {
+    start: Instant,
+    offered: u32,
+    accepted: u32,
+    last_offered: u32,
+}
+
+/// Cap danmaku throughput of each group per second
+struct GroupCap<C> {
+    cap: u32,
+    policy: OverflowPolicy,
+    clock: C,
+    rng: StdRng,
+    windows: HashMap<SmolStr, Window>,
+    recent: HashMap<(SmolStr, Arc<str>), Instant>,
+}
+
+impl<C: Clock> GroupCap<C> {
+    const WINDOW: Duration = Duration::from_secs(1);
+    /// Senders who posted within this period are not considered fresh
+    const RECENT: Duration = Duration::from_secs(30);
+
+    fn new(cap: u32, policy: OverflowPolicy, clock: C, rng: StdRng) -> Self {
+        Self {
+            cap,
+            policy,
+            clock,
+            rng,
+            windows: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    fn is_fresh(&self, key: &Option<(SmolStr, Arc<str>)>, now: Instant) -> bool {
+        key.as_ref().is_some_and(|key| {
+            self.recent
+                .get(key)
+                .is_none_or(|time| now.duration_since(*time) >= Self::RECENT)
+        })
     }
 }
 
+impl GroupCap<SystemClock> {
+    fn from_config(config: &Config) -> Option<Self> {
+        let cap = config.group_cap.try_into().ok().filter(|&cap| cap > 0)?;
+        Some(Self::new(
+            cap,
+            config.overflow_policy,
+            SystemClock,
+            StdRng::from_entropy(),
+        ))
+    }
+}
+
+impl<C: Clock> Middleware for GroupCap<C> {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        let now = self.clock.now();
+        let window = self.windows.entry(packet.group.clone()).or_insert(Window {
+            start: now,
+            offered: 0,
+            accepted: 0,
+            last_offered: 0,
+        });
+        let elapsed = now.duration_since(window.start);
+        if elapsed >= Self::WINDOW {
+            window.last_offered = if elapsed < Self::WINDOW * 2 {
+                window.offered
+            } else {
+                0
+            };
+            window.start = now;
+            window.offered = 0;
+            window.accepted = 0;
+            self.recent
+                .retain(|_, time| now.duration_since(*time) < Self::RECENT);
+        }
+        window.offered += 1;
+
+        let (accepted, last_offered) = (window.accepted, window.last_offered);
+        let key = packet
+            .danmaku
+            .sender_key()
+            .map(|sender| (packet.group.clone(), sender.clone()));
+        let keep = accepted < self.cap
+            && match self.policy {
+                OverflowPolicy::DropNewest => true,
+                OverflowPolicy::Sample => {
+                    last_offered <= self.cap
+                        || self.rng.gen_bool(self.cap as f64 / last_offered as f64)
+                }
+                OverflowPolicy::FreshSenders => {
+                    accepted < self.cap.div_ceil(2) || self.is_fresh(&key, now)
+                }
+            };
+        if !keep {
+            tracing::info!("drop overflow: {}", packet.danmaku);
+            return None;
+        }
+
+        if let Some(window) = self.windows.get_mut(&packet.group) {
+            window.accepted += 1;
+        }
+        if let Some(key) = key {
+            self.recent.insert(key, now);
+        }
+        Some(packet)
+    }
+}
+
+/// Compiled blacklist patterns
+struct Patterns {
+    patterns: Vec<String>,
+    regex: RegexSet,
+}
+
+impl Patterns {
+    fn new(patterns: Vec<String>) -> Result<Self> {
+        let regex = RegexSet::new(&patterns)?;
+        Ok(Self { patterns, regex })
+    }
+
+    fn parse(source: &str) -> Result<Self> {
+        Self::new(
+            source
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .map(Into::into)
+                .collect(),
+        )
+    }
+}
+
+/// Regex blacklist, reloadable at runtime
+pub struct Blacklist {
+    path: Option<PathBuf>,
+    patterns: RwLock<Patterns>,
+}
+
+impl Blacklist {
+    const EMBEDDED: &str = include_str!("../assets/blacklist.txt");
+
+    pub fn from_config(config: &Config) -> Self {
+        let blacklist = Self {
+            path: config.blacklist_path.clone(),
+            patterns: RwLock::new(
+                Patterns::parse(Self::EMBEDDED).expect("invalid embedded blacklist"),
+            ),
+        };
+        if let Err(e) = blacklist.reload() {
+            tracing::error!("failed to load blacklist, using embedded one: {}", e);
+        }
+        blacklist
+    }
+
+    /// Reload the blacklist from disk, keeping the previous one on error
+    pub fn reload(&self) -> Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(self.patterns.read().unwrap().patterns.len());
+        };
+        let source = std::fs::read_to_string(path)?;
+        let patterns = Patterns::parse(&source)?;
+        let len = patterns.patterns.len();
+        *self.patterns.write().unwrap() = patterns;
+        tracing::info!("loaded {} blacklist patterns from {}", len, path.display());
+        Ok(len)
+    }
+
+    /// Poll the blacklist file and reload it on change
+    pub async fn watch(self: Arc<Self>) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        watch_file(path, || {
+            if let Err(e) = self.reload() {
+                tracing::error!("failed to reload blacklist, keeping previous one: {}", e);
+            }
+        })
+        .await
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.read().unwrap().patterns.clone()
+    }
+
+    /// Add a pattern, persisting it to the blacklist file if any
+    pub fn add(&self, pattern: &str) -> Result<()> {
+        self.update(|patterns| {
+            if !patterns.iter().any(|p| p == pattern) {
+                patterns.push(pattern.into());
+            }
+        })
+    }
+
+    /// Remove a pattern, persisting it to the blacklist file if any
+    pub fn remove(&self, pattern: &str) -> Result<()> {
+        self.update(|patterns| patterns.retain(|p| p != pattern))
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Vec<String>)) -> Result<()> {
+        let mut current = self.patterns.write().unwrap();
+        let mut patterns = current.patterns.clone();
+        f(&mut patterns);
+        let patterns = Patterns::new(patterns)?;
+        if let Some(path) = &self.path {
+            let mut source = patterns.patterns.join("\n");
+            source.push('\n');
+            std::fs::write(path, source)?;
+        }
+        *current = patterns;
+        Ok(())
+    }
+
+    fn is_match(&self, text: &str) -> bool {
+        self.patterns.read().unwrap().regex.is_match(text)
+    }
+}
+
+/// Muted sender, in one group or globally
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MuteEntry {
+    /// Group the sender is muted in, or every group if unset
+    #[serde(default)]
+    pub group: Option<SmolStr>,
+    pub sender_id: Arc<str>,
+}
+
+/// Muted senders, persisted to a file if configured
+pub struct MuteList {
+    path: Option<PathBuf>,
+    entries: RwLock<HashSet<MuteEntry>>,
+}
+
+impl MuteList {
+    pub fn from_config(config: &Config) -> Self {
+        let path = config.mute_path.clone();
+        let entries = path
+            .as_deref()
+            .filter(|path| path.exists())
+            .map(|path| -> Result<HashSet<MuteEntry>> {
+                let entries = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+                Ok(entries)
+            })
+            .transpose()
+            .unwrap_or_else(|e| {
+                tracing::error!("failed to load mute list: {}", e);
+                None
+            })
+            .unwrap_or_default();
+        Self {
+            path,
+            entries: RwLock::new(entries),
+        }
+    }
+
+    pub fn entries(&self) -> Vec<MuteEntry> {
+        self.entries.read().unwrap().iter().cloned().collect()
+    }
+
+    pub fn mute(&self, entry: MuteEntry) -> Result<()> {
+        self.update(|entries| {
+            entries.insert(entry);
+        })
+    }
+
+    pub fn unmute(&self, entry: &MuteEntry) -> Result<()> {
+        self.update(|entries| {
+            entries.remove(entry);
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashSet<MuteEntry>)) -> Result<()> {
+        let mut current = self.entries.write().unwrap();
+        let mut entries = current.clone();
+        f(&mut entries);
+        if let Some(path) = &self.path {
+            std::fs::write(path, serde_json::to_string_pretty(&entries)?)?;
+        }
+        *current = entries;
+        Ok(())
+    }
+
+    fn is_muted(&self, group: &SmolStr, sender_id: &Arc<str>) -> bool {
+        let entries = self.entries.read().unwrap();
+        let mut entry = MuteEntry {
+            group: None,
+            sender_id: sender_id.clone(),
+        };
+        if entries.contains(&entry) {
+            return true;
+        }
+        entry.group = Some(group.clone());
+        entries.contains(&entry)
+    }
+}
+
+/// Moderation state shared between the middleware chain and the admin API
+pub struct Moderation {
+    pub blacklist: Arc<Blacklist>,
+    pub mutes: MuteList,
+    dedup_window: AtomicI32,
+}
+
+impl Moderation {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            blacklist: Arc::new(Blacklist::from_config(config)),
+            mutes: MuteList::from_config(config),
+            dedup_window: AtomicI32::new(config.dedup_window),
+        }
+    }
+
+    pub fn dedup_window(&self) -> i32 {
+        self.dedup_window.load(Ordering::Relaxed)
+    }
+
+    pub fn set_dedup_window(&self, window: i32) {
+        self.dedup_window.store(window, Ordering::Relaxed);
+    }
+}
+
+/// Filter danmaku by regex
+struct RegexFilter(Arc<Moderation>);
+
 impl Middleware for RegexFilter {
     #[tracing::instrument(skip(self))]
     fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
-        if self.0.is_match(&packet.danmaku.text) {
+        if self.0.blacklist.is_match(&packet.danmaku.text) {
             tracing::info!("drop blacklisted: {}", packet.danmaku);
             return None;
         }
@@ -110,21 +593,700 @@ impl Middleware for RegexFilter {
     }
 }
 
-#[tracing::instrument(skip(source, sink))]
+/// Filter danmaku by display mode, unset mode counts as scrolling
+struct ModeFilter(HashSet<Mode>);
+
+impl ModeFilter {
+    fn from_config(config: &Config) -> Option<Self> {
+        let modes = split_list(&config.allow_modes)
+            .filter_map(|mode| {
+                mode.parse()
+                    .inspect_err(|e| tracing::warn!("ignore display mode: {}", e))
+                    .ok()
+            })
+            .collect::<HashSet<_>>();
+        (!modes.is_empty()).then_some(Self(modes))
+    }
+}
+
+impl Middleware for ModeFilter {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        let mode = packet.danmaku.mode.unwrap_or(Mode::Scroll);
+        if !self.0.contains(&mode) {
+            tracing::info!("drop display mode {}: {}", mode.as_str(), packet.danmaku);
+            return None;
+        }
+
+        Some(packet)
+    }
+}
+
+/// Attach the replied danmaku to replies, and quote it before their text if enabled
+struct ReplyContext {
+    outlet: Arc<Outlet>,
+    /// Max length of the quoted text, no quote if unset
+    quote_length: Option<usize>,
+    /// Max length of danmaku, quotes included
+    max_length: usize,
+}
+
+impl ReplyContext {
+    fn from_config(config: &Config, outlet: Arc<Outlet>) -> Self {
+        Self {
+            outlet,
+            quote_length: usize::try_from(config.quote_length).ok(),
+            max_length: config.max_length,
+        }
+    }
+
+    /// Quote prefix such as `[↪ Alice: hello…] `
+    fn quote(reply: &ReplyTo, length: usize) -> String {
+        let mut text = reply.text.chars().take(length).collect::<String>();
+        if reply.text.chars().count() > length {
+            text.push('…');
+        }
+        match &reply.sender {
+            Some(sender) => format!("[↪ {}: {}] ", sender, text),
+            None => format!("[↪ {}] ", text),
+        }
+    }
+}
+
+impl Middleware for ReplyContext {
+    #[tracing::instrument(skip(self))]
+    fn run(&mut self, mut packet: DanmakuPacket) -> Option<DanmakuPacket> {
+        let Some(message_id) = &packet.in_reply_to else {
+            return Some(packet);
+        };
+        let Some(replied) = self.outlet.recent.find(&packet.group, message_id) else {
+            return Some(packet);
+        };
+        let Some(id) = replied.id else {
+            return Some(packet);
+        };
+
+        // do not nest quotes when replying to a reply, the quote may have been shortened
+        let mut text = replied.danmaku.text;
+        if let (Some(length), Some(reply)) = (self.quote_length, &replied.danmaku.reply_to) {
+            let unquoted = (1..=length)
+                .rev()
+                .find_map(|length| text.strip_prefix(&Self::quote(reply, length)));
+            if let Some(unquoted) = unquoted {
+                text = unquoted.into();
+            }
+        }
+        let reply = ReplyTo {
+            id,
+            sender: replied.danmaku.sender,
+            text,
+        };
+
+        // shorten the quoted text to stay within the max length, or leave the quote out
+        let danmaku = &mut packet.danmaku;
+        let room = self.max_length.saturating_sub(danmaku.text.chars().count());
+        let quote = self.quote_length.and_then(|length| {
+            (1..=length)
+                .rev()
+                .map(|length| Self::quote(&reply, length))
+                .find(|quote| quote.chars().count() <= room)
+        });
+        if let Some(quote) = quote {
+            danmaku.text = format!("{}{}", quote, danmaku.text).into();
+            if !danmaku.content.is_empty() {
+                danmaku
+                    .content
+                    .insert(0, Content::Text { text: quote.into() });
+            }
+        }
+        danmaku.reply_to = Some(Box::new(reply));
+
+        Some(packet)
+    }
+}
+
+/// Groups accepted from upstreams
+#[derive(Debug, Default)]
+pub struct GroupFilter {
+    /// Only these groups are accepted if set
+    allow: Option<HashSet<SmolStr>>,
+    deny: HashSet<SmolStr>,
+    dropped: AtomicU64,
+}
+
+impl GroupFilter {
+    pub fn from_config(config: &Config) -> Self {
+        let allow = split_list(&config.allow_groups)
+            .map(Into::into)
+            .collect::<HashSet<_>>();
+        Self {
+            allow: (!allow.is_empty()).then_some(allow),
+            deny: split_list(&config.deny_groups).map(Into::into).collect(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn allows(&self, group: &str) -> bool {
+        !self.deny.contains(group)
+            && self
+                .allow
+                .as_ref()
+                .is_none_or(|allow| allow.contains(group))
+    }
+
+    /// Number of packets dropped by the filter
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Entry of events from upstreams into the middleware chain
+#[derive(Clone)]
+pub struct Inlet {
+    sender: RingSender<UpstreamEvent>,
+    pub filter: Arc<GroupFilter>,
+}
+
+impl Inlet {
+    pub fn new(sender: RingSender<UpstreamEvent>, filter: GroupFilter) -> Self {
+        Self {
+            sender,
+            filter: Arc::new(filter),
+        }
+    }
+
+    pub fn send(&self, packet: DanmakuPacket) {
+        self.push(UpstreamEvent::Danmaku(packet));
+    }
+
+    /// Pass an event to the middleware chain, stamping danmaku with an id and receive time
+    pub fn push(&self, event: UpstreamEvent) {
+        let event = match event {
+            UpstreamEvent::Danmaku(mut packet) => {
+                if !self.filter.allows(&packet.group) {
+                    self.filter.dropped.fetch_add(1, Ordering::Relaxed);
+                    tracing::debug!("drop filtered group: {}", packet.group);
+                    return;
+                }
+                packet.id = Some(Ulid::new());
+                packet.received_at = Some(unix_millis(SystemTime::now()));
+                UpstreamEvent::Danmaku(packet)
+            }
+            UpstreamEvent::Recall { group, .. } if !self.filter.allows(&group) => return,
+            event => event,
+        };
+        self.sender
+            .send(event)
+            .expect("all middleware tasks are gone");
+    }
+}
+
+/// Destinations of danmaku that passed moderation
+pub struct Outlet {
+    pub sink: broadcast::Sender<DownstreamEvent>,
+    pub history: Arc<History>,
+    pub archive: Option<Arc<Archive>>,
+    pub recent: Recent,
+}
+
+impl Outlet {
+    pub fn send(&self, packet: DanmakuPacket) {
+        self.history.push(&packet);
+        self.recent.push(&packet);
+        if let Some(archive) = &self.archive {
+            archive.push(&packet);
+        }
+        self.sink.send(DownstreamEvent::Danmaku(packet)).ok();
+    }
+
+    /// Retract a delivered danmaku from history and clients, returns false if it is unknown
+    pub fn delete(&self, id: Ulid) -> bool {
+        let recent = self.recent.remove(id);
+        let history = self.history.remove(id);
+        let Some(group) = recent.or(history) else {
+            return false;
+        };
+        tracing::info!("delete {} from {}", id, group);
+        self.sink.send(DownstreamEvent::Delete { group, id }).ok();
+        true
+    }
+}
+
+/// Follow a recall from the upstream, whether the danmaku is still in review or delivered
+fn recall(outlet: &Outlet, review: &ReviewQueue, group: &str, message_id: &str) {
+    let matches = |packet: &DanmakuPacket| {
+        packet.group == group && packet.message_id.as_deref() == Some(message_id)
+    };
+    if review.withdraw(matches) {
+        return;
+    }
+    if let Some(id) = outlet
+        .recent
+        .find(group, message_id)
+        .and_then(|packet| packet.id)
+    {
+        outlet.delete(id);
+    }
+}
+
+#[tracing::instrument(skip_all)]
 pub async fn run_middleware(
-    mut source: RingReceiver<DanmakuPacket>,
-    sink: broadcast::Sender<DanmakuPacket>,
+    mut source: RingReceiver<UpstreamEvent>,
+    outlet: Arc<Outlet>,
+    moderation: Arc<Moderation>,
+    review: Arc<ReviewQueue>,
 ) {
     let config = Config::load();
 
     let mut chain = MiddlewareChain::new();
+    chain.add(Some(Normalize::from_config(&config)));
     chain.add(Some(Echo));
-    chain.add(Dedup::from_config(&config));
-    chain.add(Some(RegexFilter::new()));
+    chain.add(ModeFilter::from_config(&config));
+    chain.add(Some(Mute(moderation.clone())));
+    chain.add(Some(Dedup::new(moderation.clone())));
+    chain.add(SenderLimit::from_config(&config));
+    chain.add(Some(RegexFilter(moderation)));
+    chain.add(GroupCap::from_config(&config));
+    chain.add(Some(ReplyContext::from_config(&config, outlet.clone())));
 
-    while let Some(packet) = source.next().await {
+    while let Some(event) = source.next().await {
+        let packet = match event {
+            UpstreamEvent::Danmaku(packet) => packet,
+            UpstreamEvent::Recall { group, message_id } => {
+                recall(&outlet, &review, &group, &message_id);
+                continue;
+            }
+        };
         if let Some(packet) = chain.run(packet) {
-            sink.send(packet).ok();
+            if review.is_enabled(&packet.group) {
+                review.hold(packet);
+            } else {
+                outlet.send(packet);
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::danmaku::{Danmaku, Upstream};
+
+    use super::*;
+
+    fn packet(group: &str, sender: Option<&str>, text: &str) -> DanmakuPacket {
+        DanmakuPacket {
+            group: group.into(),
+            danmaku: Danmaku {
+                text: text.into(),
+                content: vec![],
+                color: None,
+                size: None,
+                mode: None,
+                sender: sender.map(Into::into),
+                reply_to: None,
+                sender_id: sender.map(Into::into),
+            },
+            source: Upstream::Raw,
+            id: None,
+            received_at: None,
+            sent_at: None,
+            message_id: None,
+            in_reply_to: None,
+        }
+    }
+
+    #[test]
+    fn blacklist_keeps_previous_patterns_on_bad_reload() {
+        let path = std::env::temp_dir().join(format!("danmaku-blacklist-{}.txt", Ulid::new()));
+        std::fs::write(&path, "foo\nba+r\n").unwrap();
+        let config = Config::from_vars(&[("DANMAKU_BLACKLIST_PATH", path.to_str().unwrap())]);
+        let blacklist = Blacklist::from_config(&config);
+        assert_eq!(blacklist.patterns(), ["foo", "ba+r"]);
+        assert!(blacklist.is_match("baaar"));
+
+        std::fs::write(&path, "(\n").unwrap();
+        assert!(blacklist.reload().is_err());
+        assert_eq!(blacklist.patterns(), ["foo", "ba+r"]);
+        assert!(blacklist.is_match("foo"));
+        assert!(!blacklist.is_match("hello"));
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn blacklist_falls_back_to_embedded() {
+        let config = Config::from_vars(&[("DANMAKU_BLACKLIST_PATH", "/nonexistent/blacklist.txt")]);
+        let blacklist = Blacklist::from_config(&config);
+        let embedded = Patterns::parse(Blacklist::EMBEDDED).unwrap();
+        assert_eq!(blacklist.patterns(), embedded.patterns);
+        assert!(blacklist.reload().is_err());
+        assert_eq!(blacklist.patterns(), embedded.patterns);
+    }
+
+    /// Push events through an inlet, returning the groups that reach the chain
+    fn filtered(allow: &str, deny: &str, events: Vec<UpstreamEvent>) -> (Vec<String>, u64) {
+        let config = Config::from_vars(&[
+            ("DANMAKU_ALLOW_GROUPS", allow),
+            ("DANMAKU_DENY_GROUPS", deny),
+        ]);
+        let (sender, receiver) = ring_channel::ring_channel(16.try_into().unwrap());
+        let inlet = Inlet::new(sender, GroupFilter::from_config(&config));
+        for event in events {
+            inlet.push(event);
+        }
+        let mut groups = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            groups.push(match event {
+                UpstreamEvent::Danmaku(packet) => packet.group.to_string(),
+                UpstreamEvent::Recall { group, .. } => format!("recall {group}"),
+            });
+        }
+        (groups, inlet.filter.dropped())
+    }
+
+    fn danmaku_in(groups: &[&str]) -> Vec<UpstreamEvent> {
+        groups
+            .iter()
+            .map(|group| UpstreamEvent::Danmaku(packet(group, None, "hello")))
+            .collect()
+    }
+
+    #[test]
+    fn group_filter_allows_and_denies() {
+        let all = danmaku_in(&["1", "2", "3"]);
+        assert_eq!(
+            filtered("", "", all),
+            (vec!["1".into(), "2".into(), "3".into()], 0)
+        );
+
+        let all = danmaku_in(&["1", "2", "3"]);
+        assert_eq!(filtered("1,2", "", all), (vec!["1".into(), "2".into()], 1));
+
+        let all = danmaku_in(&["1", "2", "3"]);
+        assert_eq!(filtered("", "2", all), (vec!["1".into(), "3".into()], 1));
+
+        // deny wins over allow
+        let all = danmaku_in(&["1", "2", "3"]);
+        assert_eq!(filtered("1,2", "2", all), (vec!["1".into()], 2));
+    }
+
+    #[test]
+    fn group_filter_drops_recalls() {
+        let recall = |group: &str| UpstreamEvent::Recall {
+            group: group.into(),
+            message_id: "7".into(),
+        };
+        let (groups, _) = filtered("1", "2", vec![recall("1"), recall("2"), recall("3")]);
+        assert_eq!(groups, ["recall 1"]);
+    }
+
+    #[test]
+    fn normalize_drops_blank_and_short() {
+        let mut normalize = Normalize {
+            min_length: 2,
+            rich_content: true,
+        };
+        assert!(normalize.run(packet("g", None, " \u{200B}\n ")).is_none());
+        assert!(normalize.run(packet("g", None, "a\u{200B} ")).is_none());
+        let packet = normalize
+            .run(packet("g", None, "  hello \t\n world "))
+            .unwrap();
+        assert_eq!(&*packet.danmaku.text, "hello world");
+    }
+
+    #[test]
+    fn mode_filter_drops_other_modes() {
+        let config = Config::from_vars(&[("DANMAKU_ALLOW_MODES", "scroll, bottom, sideways")]);
+        let mut chain = MiddlewareChain::new();
+        chain.add(ModeFilter::from_config(&config));
+        let mut run = |mode| {
+            let mut packet = packet("1", None, "hello");
+            packet.danmaku.mode = mode;
+            chain.run(packet).is_some()
+        };
+        assert!(run(Some(Mode::Bottom)));
+        assert!(!run(Some(Mode::Top)));
+        // unset counts as scroll
+        assert!(run(None));
+
+        let config = Config::from_vars(&[("DANMAKU_ALLOW_MODES", "top")]);
+        let mut chain = MiddlewareChain::new();
+        chain.add(ModeFilter::from_config(&config));
+        assert!(chain.run(packet("1", None, "hello")).is_none());
+
+        // no filter if nothing valid is configured
+        for modes in ["", "sideways"] {
+            let config = Config::from_vars(&[("DANMAKU_ALLOW_MODES", modes)]);
+            assert!(ModeFilter::from_config(&config).is_none());
+        }
+    }
+
+    #[test]
+    fn recall_deletes_delivered_danmaku() {
+        // recalls don't depend on the replay history
+        let config = Config::from_vars(&[("DANMAKU_HISTORY_SIZE", "0")]);
+        let sink = broadcast::channel(4).0;
+        let mut events = sink.subscribe();
+        let outlet = Arc::new(Outlet {
+            sink,
+            history: Arc::new(History::from_config(&config)),
+            archive: None,
+            recent: Recent::default(),
+        });
+        let review = ReviewQueue::from_config(&config, outlet.clone());
+
+        let id = Ulid::new();
+        let mut delivered = packet("1", Some("alice"), "hello");
+        delivered.id = Some(id);
+        delivered.message_id = Some("7".into());
+        outlet.send(delivered);
+        assert!(matches!(events.try_recv(), Ok(DownstreamEvent::Danmaku(_))));
+
+        recall(&outlet, &review, "2", "7");
+        recall(&outlet, &review, "1", "8");
+        assert!(events.try_recv().is_err());
+
+        recall(&outlet, &review, "1", "7");
+        match events.try_recv() {
+            Ok(DownstreamEvent::Delete { group, id: deleted }) => {
+                assert_eq!(group, "1");
+                assert_eq!(deleted, id);
+            }
+            event => panic!("unexpected event: {:?}", event),
+        }
+        assert!(!outlet.delete(id));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn normalize_degrades_rich_content() {
+        let image = |url: &str| {
+            let mut packet = packet("g", None, "[图片]");
+            packet.danmaku.content = vec![Content::Image {
+                url: url.into(),
+                summary: None,
+            }];
+            packet
+        };
+        let mut normalize = Normalize {
+            min_length: 1,
+            rich_content: true,
+        };
+        assert_eq!(normalize.run(image("a")).unwrap().danmaku.content.len(), 1);
+        normalize.rich_content = false;
+        let packet = normalize.run(image("a")).unwrap();
+        assert!(packet.danmaku.content.is_empty());
+        assert_eq!(&*packet.danmaku.text, "[图片]");
+    }
+
+    #[test]
+    fn dedup_tells_images_apart() {
+        let rich = |content| {
+            let mut packet = packet("g", None, "[图片]");
+            packet.danmaku.content = vec![content];
+            packet
+        };
+        let image = |url: &str| {
+            rich(Content::Image {
+                url: url.into(),
+                summary: None,
+            })
+        };
+        let face = |id: &str| {
+            rich(Content::Face {
+                id: id.into(),
+                name: None,
+                emoji: None,
+            })
+        };
+        assert_eq!(Dedup::key(&image("a")), Dedup::key(&image("a")));
+        assert_ne!(Dedup::key(&image("a")), Dedup::key(&image("b")));
+        assert_ne!(Dedup::key(&face("1")), Dedup::key(&face("2")));
+        assert_ne!(
+            Dedup::key(&image("a")),
+            Dedup::key(&packet("g", None, "[图片]"))
+        );
+    }
+
+    #[test]
+    fn reply_context_quotes_without_nesting() {
+        let outlet = Arc::new(Outlet {
+            sink: broadcast::channel(4).0,
+            history: Arc::new(History::new(10, Duration::from_secs(60), SystemClock)),
+            archive: None,
+            recent: Recent::default(),
+        });
+        let mut context = ReplyContext {
+            outlet: outlet.clone(),
+            quote_length: Some(5),
+            max_length: 20,
+        };
+        let mut deliver = |message_id: &str, in_reply_to: Option<&str>, sender, text| {
+            let mut packet = packet("g", Some(sender), text);
+            packet.id = Some(Ulid::new());
+            packet.message_id = Some(message_id.into());
+            packet.in_reply_to = in_reply_to.map(Into::into);
+            let packet = context.run(packet).unwrap();
+            outlet.send(packet.clone());
+            packet
+        };
+
+        deliver("1", None, "Alice", "hello world");
+        let reply = deliver("2", Some("1"), "Bob", "hi");
+        assert_eq!(&*reply.danmaku.text, "[↪ Alice: hello…] hi");
+        let reply = deliver("3", Some("2"), "Alice", "yo");
+        assert_eq!(&*reply.danmaku.text, "[↪ Bob: hi] yo");
+        assert_eq!(&*reply.danmaku.reply_to.unwrap().text, "hi");
+        let reply = deliver("4", Some("404"), "Bob", "what");
+        assert_eq!(&*reply.danmaku.text, "what");
+
+        // quotes count against the max length
+        let reply = deliver("5", Some("1"), "Bob", "hey");
+        assert_eq!(&*reply.danmaku.text, "[↪ Alice: hell…] hey");
+        let reply = deliver("6", Some("5"), "Alice", "sure");
+        assert_eq!(&*reply.danmaku.reply_to.unwrap().text, "hey");
+        let reply = deliver("7", Some("1"), "Bob", "a longer reply");
+        assert_eq!(&*reply.danmaku.text, "a longer reply");
+        assert!(reply.danmaku.reply_to.is_some());
+    }
+
+    fn sender_limit_chain() -> MiddlewareChain {
+        let quota =
+            Quota::per_minute(NonZeroU32::new(1).unwrap()).allow_burst(NonZeroU32::new(2).unwrap());
+        let mut chain = MiddlewareChain::new();
+        chain.add(Some(SenderLimit::new(quota)));
+        chain
+    }
+
+    #[test]
+    fn sender_limit_drops_after_burst() {
+        let mut chain = sender_limit_chain();
+        assert!(chain.run(packet("1", Some("alice"), "a")).is_some());
+        assert!(chain.run(packet("1", Some("alice"), "b")).is_some());
+        assert!(chain.run(packet("1", Some("alice"), "c")).is_none());
+    }
+
+    #[test]
+    fn sender_limit_is_per_sender_and_group() {
+        let mut chain = sender_limit_chain();
+        for text in ["a", "b", "c"] {
+            chain.run(packet("1", Some("alice"), text));
+        }
+        assert!(chain.run(packet("1", Some("bob"), "a")).is_some());
+        assert!(chain.run(packet("2", Some("alice"), "a")).is_some());
+    }
+
+    #[test]
+    fn sender_limit_follows_sender_id() {
+        let mut chain = sender_limit_chain();
+        let renamed = |name: &str, text| {
+            let mut packet = packet("1", Some(name), text);
+            packet.danmaku.sender_id = Some("10000".into());
+            packet
+        };
+        assert!(chain.run(renamed("alice", "a")).is_some());
+        assert!(chain.run(renamed("alice", "b")).is_some());
+        assert!(chain.run(renamed("bob", "c")).is_none());
+    }
+
+    #[test]
+    fn sender_limit_ignores_anonymous() {
+        let mut chain = sender_limit_chain();
+        for text in ["a", "b", "c", "d"] {
+            assert!(chain.run(packet("1", None, text)).is_some());
+        }
+    }
+
+    fn group_cap_chain(cap: u32, policy: OverflowPolicy, clock: &MockClock) -> MiddlewareChain {
+        let mut chain = MiddlewareChain::new();
+        chain.add(Some(GroupCap::new(
+            cap,
+            policy,
+            clock.clone(),
+            StdRng::seed_from_u64(0),
+        )));
+        chain
+    }
+
+    #[test]
+    fn group_cap_drops_newest() {
+        let clock = MockClock::new();
+        let mut chain = group_cap_chain(2, OverflowPolicy::DropNewest, &clock);
+        assert!(chain.run(packet("1", Some("alice"), "a")).is_some());
+        assert!(chain.run(packet("1", Some("bob"), "b")).is_some());
+        assert!(chain.run(packet("1", Some("carol"), "c")).is_none());
+        assert!(chain.run(packet("2", Some("carol"), "c")).is_some());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(chain.run(packet("1", Some("carol"), "c")).is_some());
+    }
+
+    #[test]
+    fn group_cap_samples_under_load() {
+        let clock = MockClock::new();
+        let mut chain = group_cap_chain(10, OverflowPolicy::Sample, &clock);
+
+        // first second is not sampled, so the cap is reached first-come
+        let kept = (0..100)
+            .filter(|i| chain.run(packet("1", None, &i.to_string())).is_some())
+            .count();
+        assert_eq!(kept, 10);
+
+        // overloaded in the previous second, so later danmaku still get a chance
+        clock.advance(Duration::from_secs(1));
+        let kept = (0..100)
+            .filter_map(|i| chain.run(packet("1", None, &i.to_string())))
+            .map(|packet| packet.danmaku.text.parse::<u32>().unwrap())
+            .collect::<Vec<_>>();
+        assert!(kept.len() <= 10);
+        assert!(kept.iter().any(|&i| i >= 10));
+    }
+
+    #[test]
+    fn group_cap_keys_fresh_senders_by_id() {
+        let clock = MockClock::new();
+        let mut chain = group_cap_chain(2, OverflowPolicy::FreshSenders, &clock);
+        let renamed = |name: &str, text| {
+            let mut packet = packet("1", Some(name), text);
+            packet.danmaku.sender_id = Some("10000".into());
+            packet
+        };
+        assert!(chain.run(renamed("alice", "a")).is_some());
+        assert!(chain.run(renamed("bob", "b")).is_none());
+    }
+
+    #[test]
+    fn mute_list_is_unchanged_if_not_saved() {
+        let mutes = MuteList {
+            path: Some("/nonexistent/mutes.json".into()),
+            entries: RwLock::new(HashSet::new()),
+        };
+        let entry = MuteEntry {
+            group: None,
+            sender_id: "10000".into(),
+        };
+        assert!(mutes.mute(entry.clone()).is_err());
+        assert!(!mutes.is_muted(&"1".into(), &entry.sender_id));
+    }
+
+    #[test]
+    fn group_cap_prefers_fresh_senders() {
+        let clock = MockClock::new();
+        let mut chain = group_cap_chain(4, OverflowPolicy::FreshSenders, &clock);
+        assert!(chain.run(packet("1", Some("alice"), "a")).is_some());
+        assert!(chain.run(packet("1", Some("alice"), "b")).is_some());
+        // half of the cap is used, repeat senders are dropped
+        assert!(chain.run(packet("1", Some("alice"), "c")).is_none());
+        assert!(chain.run(packet("1", None, "d")).is_none());
+        assert!(chain.run(packet("1", Some("bob"), "e")).is_some());
+        assert!(chain.run(packet("1", Some("carol"), "f")).is_some());
+        assert!(chain.run(packet("1", Some("dave"), "g")).is_none());
+
+        // alice is still recent in the next second
+        clock.advance(Duration::from_secs(1));
+        assert!(chain.run(packet("1", Some("bob"), "h")).is_some());
+        assert!(chain.run(packet("1", Some("carol"), "i")).is_some());
+        assert!(chain.run(packet("1", Some("alice"), "j")).is_none());
+        assert!(chain.run(packet("1", Some("dave"), "k")).is_some());
+    }
+}
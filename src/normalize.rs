@@ -0,0 +1,131 @@
+//! Text normalization shared by all upstreams
+
+/// Invisible characters often used to split words or pad empty messages
+fn is_zero_width(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200B}' | '\u{200C}' | '\u{200E}' | '\u{200F}' | '\u{2060}' | '\u{FEFF}'
+    )
+}
+
+/// Collapse whitespace runs into a single space and strip zero-width and control characters
+///
+/// Zero-width joiners are kept between symbols, as they join emoji sequences such as 👨‍👩‍👧.
+pub fn clean(text: &str) -> String {
+    let mut cleaned = String::with_capacity(text.len());
+    let mut space = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            space = true;
+            continue;
+        }
+        if c.is_control() || is_zero_width(c) {
+            continue;
+        }
+        if c == '\u{200D}'
+            && (space
+                || !cleaned
+                    .chars()
+                    .next_back()
+                    .is_some_and(|prev| !prev.is_ascii() && !prev.is_alphanumeric()))
+        {
+            continue;
+        }
+        if space {
+            cleaned.push(' ');
+            space = false;
+        }
+        cleaned.push(c);
+    }
+    if space {
+        cleaned.push(' ');
+    }
+    cleaned
+}
+
+/// Clean the text and trim surrounding whitespace
+pub fn normalize(text: &str) -> String {
+    clean(text).trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::danmaku::Content;
+    use crate::onebot::Message;
+
+    /// Text with whitespace, control and zero-width characters mixed in
+    fn noisy_text() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                4 => any::<char>().prop_map(String::from),
+                1 => prop::sample::select(vec![
+                    " ", "\n", "\t", "\r\n", "\u{3000}", "\u{200B}", "\u{200D}", "\u{FEFF}",
+                    "\u{0}", "\u{7F}", "👨\u{200D}👩",
+                ])
+                .prop_map(String::from),
+            ],
+            0..16,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    /// Messages in CQ code, including segments that have no text
+    fn cq_message() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                3 => noisy_text(),
+                1 => "[0-9]{1,4}".prop_map(|id| format!("[CQ:face,id={}]", id)),
+                1 => "[0-9]{5,10}".prop_map(|qq| format!("[CQ:at,qq={}]", qq)),
+                1 => "[0-9]{1,10}".prop_map(|id| format!("[CQ:reply,id={}]", id)),
+                1 => "[a-z]{1,8}".prop_map(|file| format!("[CQ:image,file={}.png]", file)),
+                1 => "[a-z]{1,8}".prop_map(|url| format!("[CQ:image,file=a,url=https://{}]", url)),
+                1 => Just("[CQ:".to_owned()),
+                1 => Just("[CQ:record,file=a.amr]".to_owned()),
+            ],
+            0..8,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    fn assert_normalized(text: &str) {
+        assert_eq!(text, text.trim());
+        assert!(!text.contains("  "));
+        assert!(text
+            .chars()
+            .all(|c| c == ' ' || !(c.is_whitespace() || c.is_control() || is_zero_width(c))));
+    }
+
+    proptest! {
+        #[test]
+        fn normalizes_arbitrary_text(text in noisy_text()) {
+            let normalized = normalize(&text);
+            assert_normalized(&normalized);
+            prop_assert_eq!(normalize(&normalized), normalized);
+        }
+
+        #[test]
+        fn normalizes_arbitrary_cq_messages(cq in cq_message()) {
+            let content = Message::Text(cq.as_str().into()).content();
+            let text = content.iter().map(Content::to_text).collect::<String>();
+            let normalized = normalize(&text);
+            assert_normalized(&normalized);
+            prop_assert_eq!(normalize(&normalized), normalized.clone());
+            // a message with anything but text never normalizes to an empty danmaku
+            if !content.iter().all(Content::is_text) {
+                prop_assert!(!normalized.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_emoji_sequences() {
+        assert_eq!(
+            normalize("\u{200B} a\u{200D}b  👨\u{200D}👩\n"),
+            "ab 👨\u{200D}👩"
+        );
+        assert_eq!(normalize("\u{3000}\u{200B}\t"), "");
+    }
+}
@@ -1,28 +1,43 @@
 use std::borrow::Cow;
+use std::collections::HashSet;
+use std::sync::Arc;
 
 use eyre::Result;
 use futures_util::StreamExt;
+use poem::http::{HeaderMap, StatusCode};
 use poem::web::websocket::{Message as WebSocketMessage, WebSocket};
-use poem::web::{Data, RemoteAddr};
-use poem::{handler, IntoResponse};
-use ring_channel::RingSender;
-use serde::Deserialize;
-use smol_str::ToSmolStr;
+use poem::web::{Data, Query, RemoteAddr};
+use poem::{handler, IntoResponse, Response};
+use serde::{Deserialize, Deserializer};
+use smol_str::{SmolStr, ToSmolStr};
 
-use crate::config::Config;
-use crate::danmaku::{Danmaku, DanmakuPacket};
-use crate::onebot::cqcode::cq_to_text;
+use crate::auth::bearer;
+use crate::config::{split_list, Config};
+use crate::danmaku::{Content, Danmaku, DanmakuPacket, Upstream, UpstreamEvent};
+use crate::middleware::Inlet;
+use crate::onebot::cqcode::parse_cq;
+use crate::onebot::face::face;
 
+mod command;
 mod cqcode;
+mod face;
+mod forward;
+mod http;
+
+pub use command::parse_command;
+pub use forward::forward;
+pub use http::onebot_http;
 
 #[derive(Deserialize, Debug, Default)]
 #[serde(default)]
 #[serde(rename_all = "snake_case")]
 pub struct MessageEvent<'a> {
     pub post_type: &'a str,
+    pub notice_type: &'a str,
     pub time: i64,
     pub self_id: i64,
     pub group_id: Option<i64>,
+    pub message_id: Option<i64>,
     pub sender: Option<Sender<'a>>,
     pub message: Option<Message<'a>>,
 }
@@ -52,50 +67,235 @@ impl<'a> Sender<'a> {
 #[serde(untagged)]
 #[serde(rename_all = "snake_case")]
 pub enum Message<'a> {
-    Text(&'a str),
+    Text(#[serde(borrow)] Cow<'a, str>),
     Segments(Vec<MessageSegment<'a>>),
 }
 
-impl<'a> Message<'a> {
-    pub fn segments(&'a self) -> Vec<Cow<'a, str>> {
+impl Message<'_> {
+    pub fn segments(&self) -> Cow<'_, [MessageSegment<'_>]> {
         match self {
-            Message::Text(text) => cq_to_text(text),
-            Message::Segments(segments) => {
-                segments.iter().map(|segment| segment.to_text()).collect()
+            Message::Text(text) => parse_cq(text).into(),
+            Message::Segments(segments) => segments.as_slice().into(),
+        }
+    }
+
+    /// Message content, with adjacent text merged
+    pub fn content(&self) -> Vec<Content> {
+        let content = self.segments();
+        let mut merged = Vec::<Content>::with_capacity(content.len());
+        for item in content.iter().filter_map(MessageSegment::to_content) {
+            match (merged.last_mut(), item) {
+                (Some(Content::Text { text }), Content::Text { text: next }) => {
+                    *text = format!("{}{}", text, next).into();
+                }
+                (_, item) => merged.push(item),
             }
         }
+        merged
+    }
+
+    /// Id of the message replied to
+    pub fn reply(&self) -> Option<SmolStr> {
+        self.segments().iter().find_map(|segment| match segment {
+            MessageSegment::Reply { id } => Some(id.clone()),
+            _ => None,
+        })
     }
 }
 
-#[derive(Deserialize, Debug)]
+/// Message segment, in the array format or parsed from CQ code
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type", content = "data")]
 #[serde(rename_all = "snake_case")]
 pub enum MessageSegment<'a> {
     Text {
-        text: &'a str,
+        #[serde(borrow)]
+        text: Cow<'a, str>,
     },
     At {
-        qq: i64,
-        name: Option<&'a str>,
+        /// QQ number, or `all`
+        #[serde(deserialize_with = "string_or_number")]
+        qq: SmolStr,
+        #[serde(borrow, default)]
+        name: Option<Cow<'a, str>>,
+    },
+    Face {
+        #[serde(deserialize_with = "string_or_number")]
+        id: SmolStr,
+    },
+    Image {
+        #[serde(borrow, default)]
+        file: Option<Cow<'a, str>>,
+        #[serde(borrow, default)]
+        url: Option<Cow<'a, str>>,
+        #[serde(borrow, default)]
+        summary: Option<Cow<'a, str>>,
+    },
+    Mface {
+        #[serde(borrow, default)]
+        url: Option<Cow<'a, str>>,
+        #[serde(borrow, default)]
+        summary: Option<Cow<'a, str>>,
+    },
+    Reply {
+        #[serde(deserialize_with = "string_or_number")]
+        id: SmolStr,
+    },
+    Record {
+        #[serde(borrow, default)]
+        file: Option<Cow<'a, str>>,
+        #[serde(borrow, default)]
+        url: Option<Cow<'a, str>>,
+    },
+    Share {
+        #[serde(borrow, default)]
+        url: Cow<'a, str>,
+        #[serde(borrow, default)]
+        title: Cow<'a, str>,
+        #[serde(borrow, default)]
+        content: Option<Cow<'a, str>>,
+        #[serde(borrow, default)]
+        image: Option<Cow<'a, str>>,
+    },
+    Json {
+        #[serde(borrow)]
+        data: Cow<'a, str>,
     },
     #[serde(other)]
     Unknown,
 }
 
-impl<'a> MessageSegment<'a> {
-    pub fn to_text(&self) -> Cow<'a, str> {
-        match self {
-            &MessageSegment::Text { text } => text.into(),
+impl MessageSegment<'_> {
+    /// Content shown in danmaku, `None` for segments that cannot be displayed
+    pub fn to_content(&self) -> Option<Content> {
+        let content = match self {
+            MessageSegment::Text { text } => Content::Text {
+                text: text.as_ref().into(),
+            },
             MessageSegment::At { qq, name } => {
-                if let Some(name) = name.filter(|s| !s.is_empty()) {
-                    format!("@{}", name).into()
-                } else {
-                    format!("@{}", qq).into()
+                let text = match name.as_deref().filter(|s| !s.is_empty()) {
+                    Some(name) => format!("@{}", name),
+                    None if qq == "all" => "@全体成员".to_owned(),
+                    None => format!("@{}", qq),
+                };
+                Content::Text { text: text.into() }
+            }
+            MessageSegment::Face { id } => face(id),
+            MessageSegment::Image { url, summary, .. } => {
+                let summary = summary.as_deref().filter(|s| !s.is_empty());
+                match url {
+                    Some(url) => Content::Image {
+                        url: url.as_ref().into(),
+                        summary: summary.map(Into::into),
+                    },
+                    None => Content::Text {
+                        text: summary.unwrap_or("[图片]").into(),
+                    },
+                }
+            }
+            MessageSegment::Mface { url, summary } => {
+                let summary = summary.as_deref().map(Into::into);
+                match url {
+                    Some(url) => Content::Image {
+                        url: url.as_ref().into(),
+                        summary,
+                    },
+                    None => Content::Text {
+                        text: summary.unwrap_or_else(|| "[表情]".into()),
+                    },
+                }
+            }
+            MessageSegment::Share { title, .. } => Content::Text {
+                text: format!("[分享]{}", title).into(),
+            },
+            MessageSegment::Json { data } => {
+                // cards carry a short description such as `[QQ小程序]哔哩哔哩`
+                let prompt = serde_json::from_str::<serde_json::Value>(data)
+                    .ok()
+                    .and_then(|card| card.get("prompt")?.as_str().map(Into::into));
+                Content::Text {
+                    text: prompt.unwrap_or_else(|| "[卡片]".into()),
                 }
             }
-            MessageSegment::Unknown => "".into(),
+            MessageSegment::Reply { .. }
+            | MessageSegment::Record { .. }
+            | MessageSegment::Unknown => return None,
+        };
+        Some(content)
+    }
+}
+
+/// Segment data values are strings in OneBot 11, but some implementations send numbers
+fn string_or_number<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SmolStr, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        String(SmolStr),
+        Number(i64),
+    }
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(s) => s,
+        Value::Number(n) => n.to_smolstr(),
+    })
+}
+
+/// Messages that never become danmaku, such as those of bots and bot commands
+pub struct IgnoreRules {
+    /// Ignore messages of the OneBot account itself
+    own: bool,
+    users: HashSet<i64>,
+    prefixes: Vec<String>,
+}
+
+impl IgnoreRules {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            own: config.ignore_self,
+            users: split_list(&config.ignore_users)
+                .filter_map(|id| {
+                    id.parse()
+                        .inspect_err(|_| tracing::warn!("ignore invalid user id: {}", id))
+                        .ok()
+                })
+                .collect(),
+            prefixes: split_list(&config.ignore_prefixes)
+                .map(Into::into)
+                .collect(),
         }
     }
+
+    fn ignores_user(&self, self_id: i64, user_id: i64) -> bool {
+        (self.own && user_id == self_id) || self.users.contains(&user_id)
+    }
+
+    /// Checked after style commands are parsed, so that `/top` is not taken for a bot command
+    fn ignores_text(&self, text: &str) -> bool {
+        self.prefixes
+            .iter()
+            .any(|prefix| text.starts_with(prefix.as_str()))
+    }
+}
+
+/// Access token passed in the query string
+#[derive(Deserialize, Debug, Default)]
+pub struct AccessTokenQuery {
+    access_token: Option<String>,
+}
+
+/// Check the access token of a OneBot connection, if one is configured
+fn authorize(
+    expected: Option<&str>,
+    headers: &HeaderMap,
+    query: &AccessTokenQuery,
+) -> Result<(), StatusCode> {
+    let Some(expected) = expected else {
+        return Ok(());
+    };
+    if bearer(headers).or(query.access_token.as_deref()) == Some(expected) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
 }
 
 #[handler]
@@ -103,20 +303,29 @@ impl<'a> MessageSegment<'a> {
 pub async fn onebot(
     ws: WebSocket,
     peer: &RemoteAddr,
-    Data(sink): Data<&RingSender<DanmakuPacket>>,
-) -> impl IntoResponse {
+    headers: &HeaderMap,
+    Query(query): Query<AccessTokenQuery>,
+    Data(sink): Data<&Inlet>,
+    Data(rules): Data<&Arc<IgnoreRules>>,
+) -> Response {
+    let config = Config::load();
+    if let Err(status) = authorize(config.onebot_token.as_deref(), headers, &query) {
+        tracing::warn!("rejected connection from {}: access token mismatch", peer);
+        return status.into_response();
+    }
+
     tracing::info!("connection from {}", peer);
     let sink = sink.clone();
-    let config = Config::load();
+    let rules = rules.clone();
     ws.on_upgrade(|mut socket| async move {
         while let Some(msg) = socket.next().await {
             let Ok(msg) = msg else { return };
             tracing::debug!("got message: {:?}", msg);
 
             if let WebSocketMessage::Text(msg) = msg {
-                match handle_message_event(msg, &config).await {
-                    Ok(Some(packet)) => {
-                        sink.send(packet).expect("all middleware tasks are gone");
+                match handle_event(msg, &config, &rules).await {
+                    Ok(Some(event)) => {
+                        sink.push(event);
                     }
                     Ok(None) => {}
                     Err(e) => tracing::error!("failed to handle message: {}", e),
@@ -124,35 +333,277 @@ pub async fn onebot(
             }
         }
     })
+    .into_response()
 }
 
-#[tracing::instrument]
-async fn handle_message_event(message: String, config: &Config) -> Result<Option<DanmakuPacket>> {
+#[tracing::instrument(skip(rules))]
+async fn handle_event(
+    message: String,
+    config: &Config,
+    rules: &IgnoreRules,
+) -> Result<Option<UpstreamEvent>> {
     let event: MessageEvent = serde_json::from_str(&message)?;
-    if event.post_type != "message" {
+    match (event.post_type, event.notice_type) {
+        ("message", _) => {}
+        ("notice", "group_recall") => {
+            let recall = event
+                .group_id
+                .zip(event.message_id)
+                .map(|(group, message_id)| UpstreamEvent::Recall {
+                    group: group.to_smolstr(),
+                    message_id: message_id.to_smolstr(),
+                });
+            return Ok(recall);
+        }
+        _ => return Ok(None),
+    }
+    if let Some(sender) = event
+        .sender
+        .as_ref()
+        .filter(|s| rules.ignores_user(event.self_id, s.user_id))
+    {
+        tracing::debug!("ignore message from {}", sender.user_id);
         return Ok(None);
     }
     if let Some(group) = event.group_id {
         if let Some(message) = event.message {
-            let message = message.segments().join("");
-            let message = message.trim();
+            let mut content = message.content();
+            let in_reply_to = message.reply();
+            let text = content.iter().map(Content::to_text).collect::<String>();
+            let text = text.trim();
+            let (style, message) = parse_command(text);
+            if rules.ignores_text(message) {
+                tracing::debug!("ignore command: {}", message);
+                return Ok(None);
+            }
             if message.chars().count() > config.max_length {
                 return Ok(None);
             }
+            if content.iter().all(Content::is_text) {
+                content.clear();
+            } else if let Some(Content::Text { text: first }) = content.first_mut() {
+                // commands only come from the leading text, drop them from the content as well
+                let consumed = text.len() - message.len();
+                if let Some(rest) = first.trim_start().get(consumed..) {
+                    *first = rest.trim_start().into();
+                }
+                if first.is_empty() {
+                    content.remove(0);
+                }
+            }
+            let sender_id = event
+                .sender
+                .as_ref()
+                .map(|sender| sender.user_id.to_string().into());
             let sender = event.sender.map(|sender| sender.name().into());
             tracing::debug!("{:?} -> {}", sender, message);
 
             let danmaku = Danmaku {
                 text: message.into(),
-                color: None, // TODO: customize
-                size: None,
+                content,
+                color: style.color,
+                size: style.size,
+                mode: style.mode,
                 sender,
+                reply_to: None,
+                sender_id,
             };
             let group = group.to_smolstr();
-            let packet = DanmakuPacket { group, danmaku };
-            return Ok(Some(packet));
+            let packet = DanmakuPacket {
+                group,
+                danmaku,
+                source: Upstream::OneBot,
+                id: None,
+                received_at: None,
+                sent_at: (event.time > 0).then(|| event.time * 1000),
+                message_id: event.message_id.map(|id| id.to_smolstr()),
+                in_reply_to,
+            };
+            return Ok(Some(UpstreamEvent::Danmaku(packet)));
         }
     }
 
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(access_token: Option<&str>) -> AccessTokenQuery {
+        AccessTokenQuery {
+            access_token: access_token.map(String::from),
+        }
+    }
+
+    #[test]
+    fn authorizes_by_access_token() {
+        let none = HeaderMap::new();
+        let mut header = HeaderMap::new();
+        header.insert(
+            poem::http::header::AUTHORIZATION,
+            "Bearer secret".parse().unwrap(),
+        );
+
+        // open without a configured token
+        assert_eq!(authorize(None, &none, &query(None)), Ok(()));
+        assert_eq!(
+            authorize(Some("secret"), &none, &query(None)),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authorize(Some("secret"), &none, &query(Some("wrong"))),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+        assert_eq!(
+            authorize(Some("secret"), &none, &query(Some("secret"))),
+            Ok(())
+        );
+        assert_eq!(authorize(Some("secret"), &header, &query(None)), Ok(()));
+        // the header takes precedence over the query
+        assert_eq!(
+            authorize(Some("secret"), &header, &query(Some("wrong"))),
+            Ok(())
+        );
+        assert_eq!(
+            authorize(Some("other"), &header, &query(Some("other"))),
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+
+    #[test]
+    fn parses_rich_segments() {
+        let message: Message = serde_json::from_str(
+            r#"[
+                {"type": "text", "data": {"text": "hi "}},
+                {"type": "at", "data": {"qq": 42, "name": "Alice"}},
+                {"type": "face", "data": {"id": "14"}},
+                {"type": "image", "data": {"file": "a.png", "url": "https:\/\/example.com\/a.png"}},
+                {"type": "mface", "data": {"summary": "[开心]"}}
+            ]"#,
+        )
+        .unwrap();
+        let content = message.content();
+        assert_eq!(
+            content,
+            vec![
+                Content::Text {
+                    text: "hi @Alice".into()
+                },
+                Content::Face {
+                    id: "14".into(),
+                    name: Some("微笑".into()),
+                    emoji: Some("🙂".into()),
+                },
+                Content::Image {
+                    url: "https://example.com/a.png".into(),
+                    summary: None,
+                },
+                Content::Text {
+                    text: "[开心]".into()
+                },
+            ]
+        );
+        let text = content.iter().map(Content::to_text).collect::<String>();
+        assert_eq!(text, "hi @Alice🙂[图片][开心]");
+    }
+
+    #[test]
+    fn parses_rich_cq_codes() {
+        let message = Message::Text(
+            "[CQ:face,id=999][CQ:image,file=a.png,url=https://example.com/a.png?a=1&amp;b=2]"
+                .into(),
+        );
+        assert_eq!(
+            message.content(),
+            vec![
+                Content::Face {
+                    id: "999".into(),
+                    name: None,
+                    emoji: None,
+                },
+                Content::Image {
+                    url: "https://example.com/a.png?a=1&b=2".into(),
+                    summary: None,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn converts_group_recalls() {
+        let config = Config::from_vars(&[]);
+        let rules = IgnoreRules::from_config(&config);
+        let event = r#"{
+            "post_type": "notice",
+            "notice_type": "group_recall",
+            "time": 1700000000,
+            "self_id": 10000,
+            "group_id": 123456,
+            "user_id": 42,
+            "operator_id": 42,
+            "message_id": 7
+        }"#;
+        let Some(UpstreamEvent::Recall { group, message_id }) =
+            handle_event(event.into(), &config, &rules).await.unwrap()
+        else {
+            panic!("no recall");
+        };
+        assert_eq!(group, "123456");
+        assert_eq!(message_id, "7");
+
+        let event = r#"{"post_type": "notice", "notice_type": "group_increase", "group_id": 1}"#;
+        assert!(handle_event(event.into(), &config, &rules)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn strips_commands_from_rich_content() {
+        let event = r##"{
+            "post_type": "message",
+            "group_id": 1,
+            "message": "#red [CQ:face,id=76]"
+        }"##;
+        let Some(UpstreamEvent::Danmaku(packet)) = handle_event(
+            event.into(),
+            &Config::from_vars(&[]),
+            &IgnoreRules {
+                own: true,
+                users: HashSet::new(),
+                prefixes: vec![],
+            },
+        )
+        .await
+        .unwrap() else {
+            panic!("no danmaku");
+        };
+        assert_eq!(packet.danmaku.color.as_deref(), Some("red"));
+        assert_eq!(&*packet.danmaku.text, "👍");
+        assert!(matches!(
+            packet.danmaku.content.as_slice(),
+            [Content::Face { .. }]
+        ));
+    }
+
+    #[test]
+    fn ignores_bots_and_commands() {
+        let mut rules = IgnoreRules {
+            own: true,
+            users: HashSet::from([20000]),
+            prefixes: vec!["/".into(), "!".into()],
+        };
+        assert!(rules.ignores_user(10000, 10000));
+        assert!(rules.ignores_user(10000, 20000));
+        assert!(!rules.ignores_user(10000, 42));
+        rules.own = false;
+        assert!(!rules.ignores_user(10000, 10000));
+
+        let (_, text) = parse_command("/top hello");
+        assert!(!rules.ignores_text(text));
+        let (_, text) = parse_command("/help");
+        assert!(rules.ignores_text(text));
+        assert!(rules.ignores_text("!roll 1d6"));
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+
+use crate::danmaku::Mode;
+
+/// Font size of `/big` danmaku
+const BIG_SIZE: f64 = 60.0;
+/// Font size of `/small` danmaku
+const SMALL_SIZE: f64 = 28.0;
+
+/// Danmaku style set by chat commands
+#[derive(Debug, Default, PartialEq)]
+pub struct Style {
+    pub color: Option<Arc<str>>,
+    pub size: Option<f64>,
+    pub mode: Option<Mode>,
+}
+
+enum Command {
+    Color(&'static str),
+    Hex,
+    Size(f64),
+    Mode(Mode),
+}
+
+fn lookup(name: &str) -> Option<Command> {
+    let command = match name {
+        "red" | "红" | "红色" => Command::Color("red"),
+        "orange" | "橙" | "橙色" => Command::Color("orange"),
+        "yellow" | "黄" | "黄色" => Command::Color("yellow"),
+        "green" | "绿" | "绿色" => Command::Color("green"),
+        "cyan" | "青" | "青色" => Command::Color("cyan"),
+        "blue" | "蓝" | "蓝色" => Command::Color("blue"),
+        "purple" | "紫" | "紫色" => Command::Color("purple"),
+        "pink" | "粉" | "粉色" => Command::Color("pink"),
+        "white" | "白" | "白色" => Command::Color("white"),
+        "big" | "大" | "大字" => Command::Size(BIG_SIZE),
+        "small" | "小" | "小字" => Command::Size(SMALL_SIZE),
+        "top" | "顶" | "顶部" => Command::Mode(Mode::Top),
+        "bottom" | "底" | "底部" => Command::Mode(Mode::Bottom),
+        "scroll" | "滚动" => Command::Mode(Mode::Scroll),
+        // the short `#rgb` form would catch words like `#bad` and `#add`
+        _ if name.len() == 6 && name.chars().all(|c| c.is_ascii_hexdigit()) => Command::Hex,
+        _ => return None,
+    };
+    Some(command)
+}
+
+/// Parse leading style commands such as `#red 大字 hello` or `/top /big hello`
+///
+/// Commands are whitespace separated words prefixed by `#` or `/`. Once the first
+/// prefixed command is seen, bare command words are accepted as well. Parsing stops
+/// at the first unknown word, and the message is left untouched if nothing remains.
+pub fn parse_command(text: &str) -> (Style, &str) {
+    let mut style = Style::default();
+    let mut rest = text.trim_start();
+    let mut started = false;
+
+    while let Some(word) = rest.split_whitespace().next() {
+        let name = match word.strip_prefix(['#', '/']) {
+            Some(name) => name,
+            None if started => word,
+            None => break,
+        };
+        let Some(command) = lookup(name) else {
+            break;
+        };
+        match command {
+            Command::Color(color) => style.color = Some(color.into()),
+            Command::Hex if word.starts_with('#') => style.color = Some(word.into()),
+            Command::Hex => break,
+            Command::Size(size) => style.size = Some(size),
+            Command::Mode(mode) => style.mode = Some(mode),
+        }
+        started = true;
+        rest = rest[word.len()..].trim_start();
+    }
+
+    if rest.is_empty() {
+        return (Style::default(), text);
+    }
+    (style, rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_prefixed_commands() {
+        let (style, text) = parse_command("/top /big hello world");
+        assert_eq!(text, "hello world");
+        assert_eq!(style.mode, Some(Mode::Top));
+        assert_eq!(style.size, Some(BIG_SIZE));
+        assert_eq!(style.color, None);
+    }
+
+    #[test]
+    fn accepts_bare_words_after_first_command() {
+        let (style, text) = parse_command("#red 大字 hello");
+        assert_eq!(text, "hello");
+        assert_eq!(style.color.as_deref(), Some("red"));
+        assert_eq!(style.size, Some(BIG_SIZE));
+    }
+
+    #[test]
+    fn parses_hex_colors() {
+        let (style, text) = parse_command("#ff8800 底部 你好");
+        assert_eq!(text, "你好");
+        assert_eq!(style.color.as_deref(), Some("#ff8800"));
+        assert_eq!(style.mode, Some(Mode::Bottom));
+
+        let (style, text) = parse_command("/abc hello");
+        assert_eq!(text, "/abc hello");
+        assert_eq!(style, Style::default());
+
+        let (style, text) = parse_command("#bad day");
+        assert_eq!(text, "#bad day");
+        assert_eq!(style, Style::default());
+    }
+
+    #[test]
+    fn later_commands_win() {
+        let (style, text) = parse_command("#red #blue /small hi");
+        assert_eq!(text, "hi");
+        assert_eq!(style.color.as_deref(), Some("blue"));
+        assert_eq!(style.size, Some(SMALL_SIZE));
+    }
+
+    #[test]
+    fn unknown_commands_pass_through() {
+        let (style, text) = parse_command("/help me");
+        assert_eq!(text, "/help me");
+        assert_eq!(style, Style::default());
+
+        let (style, text) = parse_command("#red /help me");
+        assert_eq!(text, "/help me");
+        assert_eq!(style.color.as_deref(), Some("red"));
+    }
+
+    #[test]
+    fn bare_words_alone_are_text() {
+        let (style, text) = parse_command("大字 好看");
+        assert_eq!(text, "大字 好看");
+        assert_eq!(style, Style::default());
+    }
+
+    #[test]
+    fn commands_without_text_are_text() {
+        let (style, text) = parse_command("#red");
+        assert_eq!(text, "#red");
+        assert_eq!(style, Style::default());
+
+        let (style, text) = parse_command("/top 大字");
+        assert_eq!(text, "/top 大字");
+        assert_eq!(style, Style::default());
+    }
+}
@@ -1,48 +1,304 @@
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Display, Write};
 
-use logos::Logos;
-
-#[derive(Logos, Debug, PartialEq)]
-enum CQSegment<'a> {
-    #[regex(r#"\[CQ:at,([^]]+)\]"#)]
-    At(&'a str),
-    #[regex(r#"[^\[]+"#)]
-    #[regex(r#"\["#)]
-    Text(&'a str),
-    #[regex(r#"\[CQ:([^]]+)\]"#)]
-    Unknown,
+use crate::onebot::MessageSegment;
+
+/// Unescape `&amp;`, `&#91;`, `&#93;` and `&#44;` in CQ code text or parameters
+pub fn unescape(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return text.into();
+    }
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let (c, len) = if rest.starts_with("&amp;") {
+            ('&', 5)
+        } else if rest.starts_with("&#91;") {
+            ('[', 5)
+        } else if rest.starts_with("&#93;") {
+            (']', 5)
+        } else if rest.starts_with("&#44;") {
+            (',', 5)
+        } else {
+            ('&', 1)
+        };
+        unescaped.push(c);
+        rest = &rest[len..];
+    }
+    unescaped.push_str(rest);
+    unescaped.into()
+}
+
+/// Escape CQ code text, parameters also have `,` escaped
+pub fn escape(text: &str, param: bool) -> Cow<'_, str> {
+    let special = |c| matches!(c, '&' | '[' | ']') || (param && c == ',');
+    if !text.contains(special) {
+        return text.into();
+    }
+    let mut escaped = String::with_capacity(text.len() + 8);
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '[' => escaped.push_str("&#91;"),
+            ']' => escaped.push_str("&#93;"),
+            ',' if param => escaped.push_str("&#44;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped.into()
 }
 
-macro_rules! get_cq_arg {
-    ($code:expr, $name:literal) => {
-        $code
-            .split_once(concat!(",", $name, "="))
-            .and_then(|(_, v)| v.split_once([',', ']']))
-            .map(|(v, _)| htmlize::unescape(v))
+/// Parse a message in CQ code, such as `hello [CQ:at,qq=10000]`
+///
+/// Malformed codes are kept as text, unsupported ones become [`MessageSegment::Unknown`].
+pub fn parse_cq(cqcode: &str) -> Vec<MessageSegment<'_>> {
+    let mut segments = vec![];
+    let mut rest = cqcode;
+    while !rest.is_empty() {
+        let start = rest.find("[CQ:").unwrap_or(rest.len());
+        let code = rest[start..]
+            .strip_prefix("[CQ:")
+            .and_then(|code| code.split_once(']'));
+        let Some((code, after)) = code else {
+            push_text(&mut segments, rest);
+            break;
+        };
+        push_text(&mut segments, &rest[..start]);
+        segments.push(parse_code(code));
+        rest = after;
+    }
+    segments
+}
+
+fn push_text<'a>(segments: &mut Vec<MessageSegment<'a>>, text: &'a str) {
+    if !text.is_empty() {
+        segments.push(MessageSegment::Text {
+            text: unescape(text),
+        });
+    }
+}
+
+/// Parse the inside of a CQ code, such as `at,qq=10000`
+fn parse_code(code: &str) -> MessageSegment<'_> {
+    let mut parts = code.split(',');
+    let kind = parts.next().unwrap_or_default();
+    let mut params = parts
+        .filter_map(|param| param.split_once('='))
+        .map(|(key, value)| (key, unescape(value)))
+        .collect::<HashMap<_, _>>();
+    let mut take = |key| params.remove(key);
+    let segment = match kind {
+        "text" => take("text").map(|text| MessageSegment::Text { text }),
+        "at" => take("qq").map(|qq| MessageSegment::At {
+            qq: qq.as_ref().into(),
+            name: take("name"),
+        }),
+        "face" => take("id").map(|id| MessageSegment::Face {
+            id: id.as_ref().into(),
+        }),
+        "image" => Some(MessageSegment::Image {
+            file: take("file"),
+            url: take("url"),
+            summary: take("summary"),
+        }),
+        "mface" => Some(MessageSegment::Mface {
+            url: take("url"),
+            summary: take("summary"),
+        }),
+        "reply" => take("id").map(|id| MessageSegment::Reply {
+            id: id.as_ref().into(),
+        }),
+        "record" => Some(MessageSegment::Record {
+            file: take("file"),
+            url: take("url"),
+        }),
+        "share" => Some(MessageSegment::Share {
+            url: take("url").unwrap_or_default(),
+            title: take("title").unwrap_or_default(),
+            content: take("content"),
+            image: take("image"),
+        }),
+        "json" => take("data").map(|data| MessageSegment::Json { data }),
+        _ => None,
     };
+    segment.unwrap_or(MessageSegment::Unknown)
 }
 
-impl<'a> CQSegment<'a> {
-    pub fn to_text(&self) -> Cow<'a, str> {
-        match self {
-            CQSegment::At(at) => {
-                if let Some(name) = get_cq_arg!(at, "name") {
-                    format!("@{}", name).into()
-                } else if let Some(qq) = get_cq_arg!(at, "qq") {
-                    format!("@{}", qq).into()
-                } else {
-                    "".into()
-                }
+/// Format as CQ code, the inverse of [`parse_cq`]
+impl Display for MessageSegment<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (kind, params): (_, &[(&str, Option<&str>)]) = match self {
+            MessageSegment::Text { text } => return f.write_str(&escape(text, false)),
+            MessageSegment::At { qq, name } => (
+                "at",
+                &[("qq", Some(qq.as_str())), ("name", name.as_deref())],
+            ),
+            MessageSegment::Face { id } => ("face", &[("id", Some(id.as_str()))]),
+            MessageSegment::Image { file, url, summary } => (
+                "image",
+                &[
+                    ("file", file.as_deref()),
+                    ("url", url.as_deref()),
+                    ("summary", summary.as_deref()),
+                ],
+            ),
+            MessageSegment::Mface { url, summary } => (
+                "mface",
+                &[("url", url.as_deref()), ("summary", summary.as_deref())],
+            ),
+            MessageSegment::Reply { id } => ("reply", &[("id", Some(id.as_str()))]),
+            MessageSegment::Record { file, url } => (
+                "record",
+                &[("file", file.as_deref()), ("url", url.as_deref())],
+            ),
+            MessageSegment::Share {
+                url,
+                title,
+                content,
+                image,
+            } => (
+                "share",
+                &[
+                    ("url", Some(url.as_ref())),
+                    ("title", Some(title.as_ref())),
+                    ("content", content.as_deref()),
+                    ("image", image.as_deref()),
+                ],
+            ),
+            MessageSegment::Json { data } => ("json", &[("data", Some(data.as_ref()))]),
+            MessageSegment::Unknown => return Ok(()),
+        };
+        write!(f, "[CQ:{}", kind)?;
+        for (key, value) in params {
+            if let Some(value) = value {
+                write!(f, ",{}={}", key, escape(value, true))?;
             }
-            &CQSegment::Text(text) => text.into(),
-            CQSegment::Unknown => "".into(),
         }
+        f.write_char(']')
     }
 }
 
-pub fn cq_to_text(cqcode: &str) -> Vec<Cow<str>> {
-    CQSegment::lexer(cqcode)
-        .flatten()
-        .map(|s| s.to_text())
-        .collect()
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn segment() -> impl Strategy<Value = MessageSegment<'static>> {
+        let text = || any::<String>().prop_map(Cow::Owned);
+        prop_oneof![
+            ".+".prop_map(|text| MessageSegment::Text { text: text.into() }),
+            ("[0-9]{5,10}", proptest::option::of(text())).prop_map(|(qq, name)| {
+                MessageSegment::At {
+                    qq: qq.into(),
+                    name,
+                }
+            }),
+            "[0-9]{1,3}".prop_map(|id| MessageSegment::Face { id: id.into() }),
+            (
+                proptest::option::of(text()),
+                proptest::option::of(text()),
+                proptest::option::of(text())
+            )
+                .prop_map(|(file, url, summary)| MessageSegment::Image {
+                    file,
+                    url,
+                    summary
+                }),
+            "-?[0-9]{1,10}".prop_map(|id| MessageSegment::Reply { id: id.into() }),
+            (text(), text(), proptest::option::of(text())).prop_map(|(url, title, content)| {
+                MessageSegment::Share {
+                    url,
+                    title,
+                    content,
+                    image: None,
+                }
+            }),
+            text().prop_map(|data| MessageSegment::Json { data }),
+        ]
+    }
+
+    #[test]
+    fn parses_segments() {
+        let segments = parse_cq(
+            "[CQ:reply,id=12]hi &#91;x&#93; [CQ:at,qq=10000,name=A&#44;B][CQ:face,id=14][CQ:image,file=a.png,url=https://a.com/?a=1&amp;b=2][CQ:record,file=a.amr][CQ:share,url=https://a.com,title=T][CQ:json,data={\"prompt\":\"&#91;卡片&#93;\"&#44;\"a\":1}][CQ:foo,bar=1][CQ:",
+        );
+        assert_eq!(
+            segments,
+            vec![
+                MessageSegment::Reply { id: "12".into() },
+                MessageSegment::Text {
+                    text: "hi [x] ".into()
+                },
+                MessageSegment::At {
+                    qq: "10000".into(),
+                    name: Some("A,B".into()),
+                },
+                MessageSegment::Face { id: "14".into() },
+                MessageSegment::Image {
+                    file: Some("a.png".into()),
+                    url: Some("https://a.com/?a=1&b=2".into()),
+                    summary: None,
+                },
+                MessageSegment::Record {
+                    file: Some("a.amr".into()),
+                    url: None,
+                },
+                MessageSegment::Share {
+                    url: "https://a.com".into(),
+                    title: "T".into(),
+                    content: None,
+                    image: None,
+                },
+                MessageSegment::Json {
+                    data: r#"{"prompt":"[卡片]","a":1}"#.into(),
+                },
+                MessageSegment::Unknown,
+                MessageSegment::Text {
+                    text: "[CQ:".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_first_parameter() {
+        assert_eq!(
+            parse_cq("[CQ:at,qq=42]"),
+            vec![MessageSegment::At {
+                qq: "42".into(),
+                name: None,
+            }]
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn escaping_round_trips(text in any::<String>()) {
+            for param in [false, true] {
+                let escaped = escape(&text, param);
+                prop_assert_eq!(unescape(&escaped), text.as_str());
+            }
+            prop_assert!(!escape(&text, true).contains([',', '[', ']']));
+        }
+
+        #[test]
+        fn segments_round_trip(segments in proptest::collection::vec(segment(), 0..6)) {
+            // adjacent text segments cannot be told apart in CQ code
+            let segments = segments.into_iter().fold(vec![], |mut merged: Vec<_>, segment| {
+                match (merged.last_mut(), segment) {
+                    (Some(MessageSegment::Text { text }), MessageSegment::Text { text: next }) => {
+                        *text = format!("{}{}", text, next).into();
+                    }
+                    (_, segment) => merged.push(segment),
+                }
+                merged
+            });
+            let cqcode = segments.iter().map(ToString::to_string).collect::<String>();
+            prop_assert_eq!(parse_cq(&cqcode), segments);
+        }
+    }
 }
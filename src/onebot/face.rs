@@ -0,0 +1,80 @@
+use smol_str::SmolStr;
+
+use crate::danmaku::Content;
+
+/// Name and emoji of common QQ built-in faces
+fn lookup(id: &str) -> Option<(&'static str, Option<&'static str>)> {
+    let face = match id {
+        "0" => ("惊讶", Some("😮")),
+        "1" => ("撇嘴", Some("😕")),
+        "2" => ("色", Some("😍")),
+        "3" => ("发呆", Some("😳")),
+        "4" => ("得意", Some("😎")),
+        "5" => ("流泪", Some("😢")),
+        "6" => ("害羞", Some("😊")),
+        "7" => ("闭嘴", Some("🤐")),
+        "8" => ("睡", Some("😴")),
+        "9" => ("大哭", Some("😭")),
+        "10" => ("尴尬", Some("😅")),
+        "11" => ("发怒", Some("😡")),
+        "12" => ("调皮", Some("😜")),
+        "13" => ("呲牙", Some("😁")),
+        "14" => ("微笑", Some("🙂")),
+        "15" => ("难过", Some("🙁")),
+        "16" => ("酷", Some("😎")),
+        "21" => ("可爱", Some("😊")),
+        "23" => ("傲慢", None),
+        "24" => ("饥饿", None),
+        "25" => ("困", Some("😪")),
+        "26" => ("惊恐", Some("😱")),
+        "27" => ("流汗", Some("😓")),
+        "28" => ("憨笑", Some("😄")),
+        "29" => ("悠闲", None),
+        "30" => ("奋斗", Some("💪")),
+        "31" => ("咒骂", Some("🤬")),
+        "32" => ("疑问", Some("❓")),
+        "33" => ("嘘", Some("🤫")),
+        "34" => ("晕", Some("😵")),
+        "36" => ("衰", None),
+        "37" => ("骷髅", Some("💀")),
+        "38" => ("敲打", None),
+        "39" => ("再见", Some("👋")),
+        "41" => ("发抖", None),
+        "42" => ("爱情", Some("💕")),
+        "49" => ("拥抱", Some("🤗")),
+        "53" => ("蛋糕", Some("🎂")),
+        "60" => ("咖啡", Some("☕")),
+        "63" => ("玫瑰", Some("🌹")),
+        "64" => ("凋谢", Some("🥀")),
+        "66" => ("爱心", Some("❤️")),
+        "67" => ("心碎", Some("💔")),
+        "74" => ("太阳", Some("☀️")),
+        "75" => ("月亮", Some("🌙")),
+        "76" => ("赞", Some("👍")),
+        "77" => ("踩", Some("👎")),
+        "78" => ("握手", Some("🤝")),
+        "79" => ("胜利", Some("✌️")),
+        "96" => ("冷汗", Some("😰")),
+        "99" => ("鼓掌", Some("👏")),
+        "101" => ("坏笑", Some("😏")),
+        "104" => ("哈欠", Some("🥱")),
+        "106" => ("委屈", Some("🥺")),
+        "111" => ("可怜", None),
+        "178" => ("斜眼笑", None),
+        "179" => ("doge", Some("🐶")),
+        "182" => ("笑哭", Some("😂")),
+        "212" => ("托腮", None),
+        _ => return None,
+    };
+    Some(face)
+}
+
+/// Built-in face content, with its name and emoji if known
+pub fn face(id: &str) -> Content {
+    let (name, emoji) = lookup(id).unzip();
+    Content::Face {
+        id: id.into(),
+        name: name.map(SmolStr::new_static),
+        emoji: emoji.flatten().map(SmolStr::new_static),
+    }
+}
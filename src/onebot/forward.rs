@@ -0,0 +1,151 @@
+//! OneBot 11 forward WebSocket client
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use eyre::Result;
+use futures_util::StreamExt;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+use crate::config::Config;
+use crate::middleware::Inlet;
+use crate::onebot::{handle_event, IgnoreRules};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Dial a OneBot forward WebSocket server and feed its events to the sink, reconnecting forever
+#[tracing::instrument(skip(token, sink, rules))]
+pub async fn forward(url: String, token: Option<String>, sink: Inlet, rules: Arc<IgnoreRules>) {
+    let config = Config::load();
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect(&url, token.as_deref()).await {
+            Ok(stream) => {
+                tracing::info!("connected to {}", url);
+                backoff = MIN_BACKOFF;
+                match receive(stream, &sink, &config, &rules).await {
+                    Ok(()) => tracing::warn!("connection to {} closed", url),
+                    Err(e) => tracing::warn!("connection to {} lost: {}", url, e),
+                }
+            }
+            Err(e) => tracing::warn!("failed to connect to {}: {}", url, e),
+        }
+
+        tracing::info!("reconnect to {} in {:?}", url, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect(
+    url: &str,
+    token: Option<&str>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut request = url.into_client_request()?;
+    if let Some(token) = token {
+        request
+            .headers_mut()
+            .insert(AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+    let (stream, _) = tokio_tungstenite::connect_async(request).await?;
+    Ok(stream)
+}
+
+async fn receive(
+    mut stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    sink: &Inlet,
+    config: &Config,
+    rules: &IgnoreRules,
+) -> Result<()> {
+    while let Some(msg) = stream.next().await {
+        let msg = msg?;
+        tracing::debug!("got message: {:?}", msg);
+
+        if let Message::Text(msg) = msg {
+            match handle_event(msg, config, rules).await {
+                Ok(Some(event)) => {
+                    sink.push(event);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::error!("failed to handle message: {}", e),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::SinkExt;
+    use ring_channel::ring_channel;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+    use super::*;
+    use crate::danmaku::UpstreamEvent;
+    use crate::middleware::GroupFilter;
+
+    const EVENT: &str = r#"{
+        "post_type": "message",
+        "time": 1700000000,
+        "self_id": 10000,
+        "group_id": 123456,
+        "message_id": 7,
+        "sender": {"user_id": 42, "nickname": "Alice", "card": ""},
+        "message": [{"type": "text", "data": {"text": "hello"}}]
+    }"#;
+
+    /// Accept one OneBot connection, check its token and send one event
+    #[allow(clippy::result_large_err)]
+    async fn serve_once(listener: &TcpListener, close: bool) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut socket = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, res| {
+            let auth = req.headers().get(AUTHORIZATION).unwrap();
+            assert_eq!(auth, "Bearer secret");
+            Ok::<Response, _>(res)
+        })
+        .await
+        .unwrap();
+        if close {
+            socket.close(None).await.unwrap();
+            return;
+        }
+        socket.send(Message::Text(EVENT.into())).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn receives_events_and_reconnects() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let (sink, mut source) = ring_channel(4.try_into().unwrap());
+        let sink = Inlet::new(sink, GroupFilter::default());
+        let rules = Arc::new(IgnoreRules {
+            own: true,
+            users: Default::default(),
+            prefixes: vec![],
+        });
+        tokio::spawn(forward(url, Some("secret".into()), sink, rules));
+
+        // the first connection is dropped by the server, the client dials again
+        serve_once(&listener, true).await;
+        serve_once(&listener, false).await;
+
+        let event = tokio::time::timeout(Duration::from_secs(5), source.next())
+            .await
+            .expect("no packet received")
+            .unwrap();
+        let UpstreamEvent::Danmaku(packet) = event else {
+            panic!("unexpected event: {:?}", event);
+        };
+        assert_eq!(packet.group, "123456");
+        assert_eq!(&*packet.danmaku.text, "hello");
+        assert_eq!(packet.danmaku.sender.as_deref(), Some("Alice"));
+        assert_eq!(packet.danmaku.sender_id.as_deref(), Some("42"));
+        assert_eq!(packet.message_id.as_deref(), Some("7"));
+    }
+}
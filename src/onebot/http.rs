@@ -0,0 +1,99 @@
+//! OneBot 11 HTTP POST event receiver
+
+use std::sync::Arc;
+
+use eyre::{eyre, Result};
+use hmac::{Hmac, Mac};
+use poem::http::{HeaderMap, StatusCode};
+use poem::web::{Data, RemoteAddr};
+use poem::{handler, IntoResponse, Response};
+use sha1::Sha1;
+
+use crate::config::Config;
+use crate::middleware::Inlet;
+use crate::onebot::{handle_event, IgnoreRules};
+
+#[handler]
+#[tracing::instrument(skip_all)]
+pub async fn onebot_http(
+    peer: &RemoteAddr,
+    headers: &HeaderMap,
+    body: Vec<u8>,
+    Data(sink): Data<&Inlet>,
+    Data(rules): Data<&Arc<IgnoreRules>>,
+) -> Response {
+    let config = Config::load();
+    if let Some(secret) = &config.onebot_secret {
+        if let Err(e) = verify_signature(secret, headers, &body) {
+            tracing::warn!("rejected event from {}: {}", peer, e);
+            return StatusCode::UNAUTHORIZED.into_response();
+        }
+    }
+
+    let Ok(message) = String::from_utf8(body) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    tracing::debug!("got message: {:?}", message);
+    match handle_event(message, &config, rules).await {
+        Ok(Some(event)) => {
+            sink.push(event);
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("failed to handle message: {}", e),
+    }
+
+    // empty quick operation
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// Verify the `X-Signature: sha1=<hmac>` header of the body
+fn verify_signature(secret: &str, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+    let signature = headers
+        .get("X-Signature")
+        .ok_or_else(|| eyre!("missing signature"))?
+        .to_str()?
+        .strip_prefix("sha1=")
+        .ok_or_else(|| eyre!("unsupported signature"))?;
+    let signature = hex::decode(signature)?;
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes())?;
+    mac.update(body);
+    mac.verify_slice(&signature)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use poem::http::HeaderValue;
+
+    use super::*;
+
+    const BODY: &[u8] = br#"{"post_type":"message","group_id":123456}"#;
+
+    fn signed_headers(secret: &str, body: &[u8]) -> HeaderMap {
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let signature = format!("sha1={}", hex::encode(mac.finalize().into_bytes()));
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature", HeaderValue::from_str(&signature).unwrap());
+        headers
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let headers = signed_headers("secret", BODY);
+        assert!(verify_signature("secret", &headers, BODY).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_signature() {
+        let headers = signed_headers("other", BODY);
+        assert!(verify_signature("secret", &headers, BODY).is_err());
+
+        let headers = signed_headers("secret", b"{}");
+        assert!(verify_signature("secret", &headers, BODY).is_err());
+
+        assert!(verify_signature("secret", &HeaderMap::new(), BODY).is_err());
+    }
+}
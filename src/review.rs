@@ -0,0 +1,385 @@
+//! Manual approval of danmaku before they are shown
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use futures_util::SinkExt;
+use poem::web::websocket::{Message, WebSocket};
+use poem::web::{Data, RemoteAddr};
+use poem::{handler, IntoResponse};
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::{split_list, Config};
+use crate::danmaku::{Danmaku, DanmakuPacket};
+use crate::middleware::Outlet;
+
+/// Update pushed to moderators
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReviewEvent {
+    Pending {
+        id: u64,
+        group: SmolStr,
+        danmaku: Danmaku,
+        sender_id: Option<Arc<str>>,
+    },
+    Resolved {
+        id: u64,
+        status: Status,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Approved,
+    Rejected,
+    Expired,
+    /// Recalled or deleted before being reviewed
+    Withdrawn,
+}
+
+/// Command sent by moderators
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+enum Command {
+    Approve { id: u64 },
+    Reject { id: u64 },
+}
+
+/// Queue of danmaku waiting for approval
+pub struct ReviewQueue<C = SystemClock> {
+    outlet: Arc<Outlet>,
+    timeout: Duration,
+    clock: C,
+    groups: RwLock<HashSet<SmolStr>>,
+    pending: Mutex<BTreeMap<u64, (Instant, DanmakuPacket)>>,
+    next_id: AtomicU64,
+    events: broadcast::Sender<ReviewEvent>,
+}
+
+impl ReviewQueue {
+    pub fn from_config(config: &Config, outlet: Arc<Outlet>) -> Self {
+        let groups = split_list(&config.review_groups).map(Into::into).collect();
+        Self::new(
+            outlet,
+            Duration::from_secs(config.review_timeout),
+            groups,
+            SystemClock,
+        )
+    }
+
+    /// Drop pending danmaku older than the timeout, every second
+    pub async fn expire(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.expire_pending();
+        }
+    }
+}
+
+impl<C: Clock> ReviewQueue<C> {
+    fn new(outlet: Arc<Outlet>, timeout: Duration, groups: HashSet<SmolStr>, clock: C) -> Self {
+        Self {
+            outlet,
+            timeout,
+            clock,
+            groups: RwLock::new(groups),
+            pending: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(0),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    pub fn is_enabled(&self, group: &str) -> bool {
+        self.groups.read().unwrap().contains(group)
+    }
+
+    pub fn groups(&self) -> Vec<SmolStr> {
+        self.groups.read().unwrap().iter().cloned().collect()
+    }
+
+    pub fn enable(&self, group: SmolStr) {
+        self.groups.write().unwrap().insert(group);
+    }
+
+    pub fn disable(&self, group: &str) {
+        self.groups.write().unwrap().remove(group);
+    }
+
+    /// Hold a danmaku until a moderator approves or rejects it
+    pub fn hold(&self, packet: DanmakuPacket) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let event = pending_event(id, &packet);
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(id, (self.clock.now(), packet));
+        self.events.send(event).ok();
+    }
+
+    /// Resolve a pending danmaku, returns false if it is not pending anymore
+    fn resolve(&self, id: u64, status: Status) -> bool {
+        let Some((_, packet)) = self.pending.lock().unwrap().remove(&id) else {
+            return false;
+        };
+        tracing::info!("{:?}: {}", status, packet.danmaku);
+        if let Status::Approved = status {
+            self.outlet.send(packet);
+        }
+        self.events.send(ReviewEvent::Resolved { id, status }).ok();
+        true
+    }
+
+    /// Drop the pending danmaku matching the predicate, returns false if there is none
+    pub fn withdraw(&self, matches: impl Fn(&DanmakuPacket) -> bool) -> bool {
+        let id = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|(_, (_, packet))| matches(packet))
+            .map(|(&id, _)| id);
+        id.is_some_and(|id| self.resolve(id, Status::Withdrawn))
+    }
+
+    /// Drop pending danmaku older than the timeout
+    fn expire_pending(&self) {
+        let now = self.clock.now();
+        let expired = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .take_while(|(_, (time, _))| now.duration_since(*time) >= self.timeout)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.resolve(id, Status::Expired);
+        }
+    }
+
+    /// Current pending danmaku and a stream of later updates
+    fn subscribe(&self) -> (Vec<ReviewEvent>, broadcast::Receiver<ReviewEvent>) {
+        let pending = self.pending.lock().unwrap();
+        let events = self.events.subscribe();
+        let snapshot = pending
+            .iter()
+            .map(|(&id, (_, packet))| pending_event(id, packet))
+            .collect();
+        (snapshot, events)
+    }
+}
+
+fn pending_event(id: u64, packet: &DanmakuPacket) -> ReviewEvent {
+    ReviewEvent::Pending {
+        id,
+        group: packet.group.clone(),
+        danmaku: packet.danmaku.clone(),
+        sender_id: packet.danmaku.sender_id.clone(),
+    }
+}
+
+#[handler]
+#[tracing::instrument(skip(ws, review))]
+pub async fn moderator(
+    ws: WebSocket,
+    RemoteAddr(peer): &RemoteAddr,
+    Data(review): Data<&Arc<ReviewQueue>>,
+) -> impl IntoResponse {
+    let peer = peer.clone();
+    tracing::info!("moderator connected from {}", peer);
+
+    let review = review.clone();
+    ws.on_upgrade(move |mut socket| async move {
+        let (snapshot, mut events) = review.subscribe();
+        for event in snapshot {
+            if let Ok(event) = serde_json::to_string(&event) {
+                let _ = socket.send(Message::Text(event)).await;
+            }
+        }
+
+        let mut ping = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            tokio::select! {
+                // From review queue
+                event = events.recv() => {
+                    match event {
+                        Ok(event) => {
+                            if let Ok(event) = serde_json::to_string(&event) {
+                                let _ = socket.send(Message::Text(event)).await;
+                            }
+                        }
+                        Err(RecvError::Lagged(n)) => {
+                            tracing::warn!("moderator {} lagged {} events", peer, n);
+                            continue;
+                        }
+                        Err(_) => break,
+                    }
+                }
+
+                // From moderator
+                Some(Ok(msg)) = socket.next() => {
+                    tracing::debug!("got message: {:?}", msg);
+                    match msg {
+                        Message::Text(msg) => match serde_json::from_str::<Command>(&msg) {
+                            Ok(Command::Approve { id }) => { review.resolve(id, Status::Approved); }
+                            Ok(Command::Reject { id }) => { review.resolve(id, Status::Rejected); }
+                            Err(e) => tracing::warn!("invalid command from {}: {}", peer, e),
+                        },
+                        Message::Ping(payload) => {
+                            let _ = socket.send(Message::Pong(payload)).await;
+                            tracing::debug!("pong");
+                        }
+                        Message::Close(close) => {
+                            tracing::info!("moderator {} disconnected: {:?}", peer, close);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+
+                // Ping
+                _ = ping.tick() => {
+                    let _ = socket.send(Message::Ping(vec![])).await;
+                    tracing::debug!("ping");
+                }
+
+                // On error
+                else => { break }
+            }
+        }
+        if let Err(e) = socket.close().await {
+            tracing::error!("failed to close connection: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use crate::clock::MockClock;
+    use crate::danmaku::DownstreamEvent;
+    use crate::history::{History, Recent};
+
+    use super::*;
+
+    struct Fixture {
+        clock: MockClock,
+        queue: ReviewQueue<MockClock>,
+        delivered: broadcast::Receiver<DownstreamEvent>,
+        events: broadcast::Receiver<ReviewEvent>,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let sink = broadcast::channel(8).0;
+            let delivered = sink.subscribe();
+            let outlet = Arc::new(Outlet {
+                sink,
+                history: Arc::new(History::new(10, Duration::from_secs(60), SystemClock)),
+                archive: None,
+                recent: Recent::default(),
+            });
+            let clock = MockClock::new();
+            let queue = ReviewQueue::new(
+                outlet,
+                Duration::from_secs(60),
+                HashSet::from(["1".into()]),
+                clock.clone(),
+            );
+            let events = queue.events.subscribe();
+            Self {
+                clock,
+                queue,
+                delivered,
+                events,
+            }
+        }
+
+        /// Hold a danmaku, returns its review id
+        fn hold(&mut self, text: &str) -> u64 {
+            let packet = serde_json::from_value(
+                serde_json::json!({ "group": "1", "danmaku": { "text": text } }),
+            )
+            .unwrap();
+            self.queue.hold(packet);
+            match self.events.try_recv().unwrap() {
+                ReviewEvent::Pending { id, .. } => id,
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        fn resolved(&mut self) -> Option<(u64, Status)> {
+            match self.events.try_recv() {
+                Ok(ReviewEvent::Resolved { id, status }) => Some((id, status)),
+                Err(TryRecvError::Empty) => None,
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+
+        fn delivered(&mut self) -> Option<Arc<str>> {
+            match self.delivered.try_recv() {
+                Ok(DownstreamEvent::Danmaku(packet)) => Some(packet.danmaku.text),
+                Err(TryRecvError::Empty) => None,
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+    }
+
+    #[test]
+    fn approve_delivers_danmaku() {
+        let mut fixture = Fixture::new();
+        let id = fixture.hold("hello");
+        assert!(fixture.delivered().is_none());
+
+        assert!(fixture.queue.resolve(id, Status::Approved));
+        assert_eq!(fixture.resolved(), Some((id, Status::Approved)));
+        assert_eq!(fixture.delivered().as_deref(), Some("hello"));
+
+        // already resolved
+        assert!(!fixture.queue.resolve(id, Status::Approved));
+        assert!(fixture.delivered().is_none());
+    }
+
+    #[test]
+    fn reject_drops_danmaku() {
+        let mut fixture = Fixture::new();
+        let id = fixture.hold("hello");
+        assert!(fixture.queue.resolve(id, Status::Rejected));
+        assert_eq!(fixture.resolved(), Some((id, Status::Rejected)));
+        assert!(fixture.delivered().is_none());
+        assert!(fixture.queue.subscribe().0.is_empty());
+    }
+
+    #[test]
+    fn expires_after_timeout() {
+        let mut fixture = Fixture::new();
+        let old = fixture.hold("old");
+        fixture.clock.advance(Duration::from_secs(30));
+        let new = fixture.hold("new");
+
+        fixture.clock.advance(Duration::from_secs(29));
+        fixture.queue.expire_pending();
+        assert!(fixture.resolved().is_none());
+
+        fixture.clock.advance(Duration::from_secs(1));
+        fixture.queue.expire_pending();
+        assert_eq!(fixture.resolved(), Some((old, Status::Expired)));
+        assert!(fixture.resolved().is_none());
+        assert!(fixture.delivered().is_none());
+
+        // the newer danmaku can still be approved until it expires too
+        assert!(fixture.queue.resolve(new, Status::Approved));
+        assert_eq!(fixture.delivered().as_deref(), Some("new"));
+    }
+}
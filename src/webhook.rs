@@ -1,18 +1,23 @@
 //! Official QQ bot WebHook
 
-use ed25519_dalek::{ed25519::signature::SignerMut, SecretKey, SigningKey};
-use eyre::Result;
+use chrono::DateTime;
+use ed25519_dalek::{
+    ed25519::signature::SignerMut, SecretKey, Signature, SigningKey, Verifier, VerifyingKey,
+};
+use eyre::{eyre, Result};
 use poem::{
     handler,
+    http::{HeaderMap, StatusCode},
     web::{Data, Json},
-    IntoResponse,
+    IntoResponse, Response,
 };
-use ring_channel::RingSender;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     config::Config,
-    danmaku::{Danmaku, DanmakuPacket},
+    danmaku::{Danmaku, DanmakuPacket, Upstream},
+    middleware::Inlet,
+    onebot::parse_command,
 };
 
 /// Integer tag support from https://github.com/serde-rs/serde/issues/745#issuecomment-1450072069
@@ -68,38 +73,39 @@ struct Message {
     content: Option<String>,
     channel_id: String,
     author: User,
+    /// Send time in RFC 3339, such as `2021-05-20T15:14:58+08:00`
+    timestamp: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 struct User {
+    id: String,
     username: String,
 }
 
 #[handler]
 #[tracing::instrument(skip_all)]
-pub async fn webhook(
-    Json(payload): Json<Payload>,
-    Data(sink): Data<&RingSender<DanmakuPacket>>,
-) -> impl IntoResponse {
+pub async fn webhook(headers: &HeaderMap, body: Vec<u8>, Data(sink): Data<&Inlet>) -> Response {
     let config = Config::load();
 
+    let payload: Payload = match serde_json::from_slice(&body) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::warn!("invalid payload: {}", e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
     tracing::debug!("payload: {:?}", payload);
 
+    // a default secret would let anyone sign dispatches
+    let Some(secret) = config.bot_secret.as_deref().filter(|s| !s.is_empty()) else {
+        tracing::warn!("rejected payload: DANMAKU_BOT_SECRET is not set");
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+    let mut signer = signing_key(secret);
+
     match payload {
         Payload::Validate { d, .. } => {
-            // Populate secret length to 32 bytes
-            let seed = config
-                .bot_secret
-                .as_bytes()
-                .iter()
-                .cycle()
-                .take(ed25519_dalek::SECRET_KEY_LENGTH)
-                .copied()
-                .collect::<Vec<u8>>();
-            let seed = SecretKey::try_from(seed).unwrap();
-
-            let mut signer = SigningKey::from(seed);
-
             let mut msg = d.event_ts.clone();
             msg.push_str(&d.plain_token);
 
@@ -116,11 +122,14 @@ pub async fn webhook(
             Json(serde_json::json!({ "op": 11, "d": d })).into_response()
         }
         Payload::Dispatch { id, d, .. } => {
-            // TODO: check signature by header (https://bot.q.qq.com/wiki/develop/api-v2/dev-prepare/interface-framework/sign.html)
+            if let Err(e) = verify_signature(&signer.verifying_key(), headers, &body) {
+                tracing::warn!("rejected dispatch {}: {}", id, e);
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
             if id.starts_with("MESSAGE_CREATE") {
                 match receive_message(&d, &config) {
                     Ok(Some(packet)) => {
-                        sink.send(packet).expect("all middleware tasks are gone");
+                        sink.send(packet);
                     }
                     Ok(None) => {}
                     Err(e) => tracing::error!("failed to handle message: {}", e),
@@ -131,6 +140,40 @@ pub async fn webhook(
     }
 }
 
+/// Derive the bot signing key from the bot secret
+fn signing_key(secret: &str) -> SigningKey {
+    // Populate secret length to 32 bytes
+    let seed = secret
+        .as_bytes()
+        .iter()
+        .cycle()
+        .take(ed25519_dalek::SECRET_KEY_LENGTH)
+        .copied()
+        .collect::<Vec<u8>>();
+    let seed = SecretKey::try_from(seed).unwrap();
+    SigningKey::from(seed)
+}
+
+/// Verify request signature, see https://bot.q.qq.com/wiki/develop/api-v2/dev-prepare/interface-framework/sign.html
+fn verify_signature(key: &VerifyingKey, headers: &HeaderMap, body: &[u8]) -> Result<()> {
+    let signature = headers
+        .get("X-Signature-Ed25519")
+        .ok_or_else(|| eyre!("missing signature"))?
+        .to_str()?;
+    let timestamp = headers
+        .get("X-Signature-Timestamp")
+        .ok_or_else(|| eyre!("missing timestamp"))?
+        .to_str()?;
+
+    let signature = hex::decode(signature)?;
+    let signature = Signature::from_slice(&signature)?;
+
+    let mut msg = timestamp.as_bytes().to_vec();
+    msg.extend_from_slice(body);
+    key.verify(&msg, &signature)?;
+    Ok(())
+}
+
 #[tracing::instrument]
 fn receive_message(data: &serde_json::Value, config: &Config) -> Result<Option<DanmakuPacket>> {
     let msg: Message = serde_json::from_value(data.clone())?;
@@ -142,23 +185,129 @@ fn receive_message(data: &serde_json::Value, config: &Config) -> Result<Option<D
 
     if let Some(message) = msg.content {
         let message = RE.with(|re| re.replace_all(&message, ""));
-        let message = message.trim();
+        let (style, message) = parse_command(message.trim());
         if message.chars().count() > config.max_length {
             return Ok(None);
         }
         let sender = Some(msg.author.username.into());
+        let sender_id = Some(msg.author.id.into());
+        let sent_at = msg.timestamp.as_deref().and_then(|timestamp| {
+            DateTime::parse_from_rfc3339(timestamp)
+                .inspect_err(|e| tracing::warn!("invalid timestamp {}: {}", timestamp, e))
+                .ok()
+        });
         tracing::debug!("{:?} -> {}", sender, message);
 
         let danmaku = Danmaku {
             text: message.into(),
-            color: None, // TODO: customize
-            size: None,
+            content: vec![],
+            color: style.color,
+            size: style.size,
+            mode: style.mode,
             sender,
+            reply_to: None,
+            sender_id,
         };
         return Ok(Some(DanmakuPacket {
             group: msg.channel_id.parse()?,
             danmaku,
+            source: Upstream::Webhook,
+            id: None,
+            received_at: None,
+            sent_at: sent_at.map(|time| time.timestamp_millis()),
+            message_id: None,
+            in_reply_to: None,
         }));
     }
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use poem::http::HeaderValue;
+
+    use super::*;
+
+    const BODY: &[u8] = br#"{"id":"MESSAGE_CREATE:1","op":0,"d":{}}"#;
+
+    fn signed_headers(signer: &mut SigningKey, timestamp: &str, body: &[u8]) -> HeaderMap {
+        let mut msg = timestamp.as_bytes().to_vec();
+        msg.extend_from_slice(body);
+        let signature = hex::encode(signer.sign(&msg).to_bytes());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "X-Signature-Ed25519",
+            HeaderValue::from_str(&signature).unwrap(),
+        );
+        headers.insert(
+            "X-Signature-Timestamp",
+            HeaderValue::from_str(timestamp).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn keeps_message_timestamp() {
+        let message = serde_json::json!({
+            "content": "<@!1234> hello",
+            "channel_id": "100",
+            "author": { "id": "42", "username": "alice" },
+            "timestamp": "2021-05-20T15:14:58+08:00",
+        });
+        let packet = receive_message(&message, &Config::from_vars(&[]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(&*packet.danmaku.text, "hello");
+        assert_eq!(packet.sent_at, Some(1621494898000));
+    }
+
+    #[test]
+    fn accepts_valid_signature() {
+        let mut signer = signing_key("naOC0ocQE3shWLAfffVLB1rhYPG7");
+        let headers = signed_headers(&mut signer, "1725442341", BODY);
+        assert!(verify_signature(&signer.verifying_key(), &headers, BODY).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_body() {
+        let mut signer = signing_key("naOC0ocQE3shWLAfffVLB1rhYPG7");
+        let headers = signed_headers(&mut signer, "1725442341", BODY);
+        let body = br#"{"id":"MESSAGE_CREATE:2","op":0,"d":{}}"#;
+        assert!(verify_signature(&signer.verifying_key(), &headers, body).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_timestamp() {
+        let mut signer = signing_key("naOC0ocQE3shWLAfffVLB1rhYPG7");
+        let mut headers = signed_headers(&mut signer, "1725442341", BODY);
+        headers.insert(
+            "X-Signature-Timestamp",
+            HeaderValue::from_static("1725442342"),
+        );
+        assert!(verify_signature(&signer.verifying_key(), &headers, BODY).is_err());
+    }
+
+    #[test]
+    fn rejects_foreign_key() {
+        let mut other = signing_key("another-secret");
+        let headers = signed_headers(&mut other, "1725442341", BODY);
+        let signer = signing_key("naOC0ocQE3shWLAfffVLB1rhYPG7");
+        assert!(verify_signature(&signer.verifying_key(), &headers, BODY).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_or_malformed_headers() {
+        let signer = signing_key("naOC0ocQE3shWLAfffVLB1rhYPG7");
+        let key = signer.verifying_key();
+        assert!(verify_signature(&key, &HeaderMap::new(), BODY).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Signature-Ed25519", HeaderValue::from_static("not-hex"));
+        headers.insert(
+            "X-Signature-Timestamp",
+            HeaderValue::from_static("1725442341"),
+        );
+        assert!(verify_signature(&key, &headers, BODY).is_err());
+    }
+}